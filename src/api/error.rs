@@ -0,0 +1,215 @@
+use std::error::Error;
+use std::fmt;
+use std::str::Utf8Error;
+use std::time::Duration;
+use serde::{Serialize, Serializer};
+use serde_json::{Value, json};
+
+/// Postgres error codes for constraint violations
+const UNIQUE_VIOLATION: &str = "23505";
+const NOT_NULL_VIOLATION: &str = "23502";
+const FOREIGN_KEY_VIOLATION: &str = "23503";
+
+/// A reason that a request could not be carried out
+#[derive(Clone, Debug, PartialEq)]
+pub enum ApiError {
+    /// The request was malformed or asked for something the server does not support
+    InvalidRequest(String),
+    /// The request is missing a field it needs, named by its path within the request
+    MissingField(String),
+    /// A field of the request could not be read, named by its path within the request
+    InvalidField {
+        field: String,
+        reason: String,
+    },
+    /// The client took too long to send a request
+    TimedOut(String),
+    /// The client speaks a version of the protocol that is too old
+    UnsupportedVersion,
+    /// The request needs a verified user
+    NotAuthenticated,
+    /// The user's email, password or token was not accepted
+    InvalidCredentials,
+    /// The user does not participate in a conversation they referred to
+    NotParticipant,
+    /// Something the request referred to does not exist
+    NotFound,
+    /// Something the request tried to create already exists
+    AlreadyExists,
+    /// The client made too many requests of a kind, and may try again after waiting
+    RateLimited(Duration),
+    /// The server is too busy to carry out the request soon, and the client should try again later
+    ServerBusy,
+    /// The server failed, with details that are logged but not sent to clients
+    Internal(String),
+}
+
+impl ApiError {
+    /// Find the stable code identifying this kind of error
+    pub fn code(&self) -> u16 {
+        match self {
+            ApiError::InvalidRequest(_) => 100,
+            ApiError::MissingField(_) => 101,
+            ApiError::TimedOut(_) => 102,
+            ApiError::InvalidField{..} => 103,
+            ApiError::UnsupportedVersion => 104,
+            ApiError::NotAuthenticated => 200,
+            ApiError::InvalidCredentials => 201,
+            ApiError::NotParticipant => 202,
+            ApiError::NotFound => 300,
+            ApiError::AlreadyExists => 301,
+            ApiError::RateLimited(_) => 400,
+            ApiError::ServerBusy => 401,
+            ApiError::Internal(_) => 500,
+        }
+    }
+
+    /// Describe the error in a way that is safe to send to clients
+    pub fn message(&self) -> String {
+        match self {
+            ApiError::InvalidRequest(m) | ApiError::TimedOut(m) => m.to_owned(),
+            ApiError::MissingField(field) => format!("Missing '{}'", field),
+            ApiError::InvalidField{field, reason} => format!("'{}' {}", field, reason),
+            ApiError::UnsupportedVersion => String::from("Unsupported protocol version"),
+            ApiError::NotAuthenticated => String::from("Not authenticated"),
+            ApiError::InvalidCredentials => String::from("Invalid credentials"),
+            ApiError::NotParticipant => String::from("Not a participant in conversation"),
+            ApiError::NotFound => String::from("Not found"),
+            ApiError::AlreadyExists => String::from("Already exists"),
+            ApiError::RateLimited(_) => String::from("Too many requests"),
+            ApiError::ServerBusy => String::from("Server busy"),
+            ApiError::Internal(_) => String::from("Internal server error"),
+        }
+    }
+
+    /// Format the error as a JSON value
+    pub fn to_value(&self) -> Value {
+        let mut error = json!({
+            "code": self.code(),
+            "message": self.message(),
+        });
+
+        if let ApiError::MissingField(field) | ApiError::InvalidField{field, ..} = self {
+            error["field"] = json!(field);
+        }
+
+        if let Some(seconds) = self.retry_after() {
+            error["retryAfter"] = json!(seconds);
+        }
+
+        error
+    }
+
+    /// Find how many whole seconds a rate limited client should wait before trying again
+    pub fn retry_after(&self) -> Option<u64> {
+        match self {
+            ApiError::RateLimited(wait) => Some(wait.as_secs_f64().ceil().max(1.0) as u64),
+            _ => None,
+        }
+    }
+
+    /// Locate an invalid field within part of a larger request
+    pub fn within(self, parent: &str) -> Self {
+        match self {
+            ApiError::MissingField(field) => ApiError::MissingField(format!("{}.{}", parent, field)),
+            ApiError::InvalidField{field, reason} => ApiError::InvalidField{
+                field: format!("{}.{}", parent, field),
+                reason,
+            },
+            e => e,
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::Internal(details) => write!(f, "Internal error: {}", details),
+            e => write!(f, "{}", e.message()),
+        }
+    }
+}
+
+impl Error for ApiError {}
+
+impl Serialize for ApiError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.to_value().serialize(serializer)
+    }
+}
+
+impl From<sqlx::Error> for ApiError {
+    fn from(error: sqlx::Error) -> Self {
+        match &error {
+            sqlx::Error::RowNotFound => ApiError::NotFound,
+            sqlx::Error::Database(e) => match e.code().as_deref() {
+                Some(UNIQUE_VIOLATION) => ApiError::AlreadyExists,
+                // Rows referring to missing users or conversations
+                Some(NOT_NULL_VIOLATION) | Some(FOREIGN_KEY_VIOLATION) => ApiError::NotFound,
+                _ => ApiError::Internal(error.to_string()),
+            },
+            _ => ApiError::Internal(error.to_string()),
+        }
+    }
+}
+
+impl From<serde_json::Error> for ApiError {
+    fn from(error: serde_json::Error) -> Self {
+        ApiError::InvalidRequest(format!("Invalid JSON: {}", error))
+    }
+}
+
+impl From<Utf8Error> for ApiError {
+    fn from(_: Utf8Error) -> Self {
+        ApiError::InvalidRequest(String::from("Request must be valid UTF-8"))
+    }
+}
+
+impl From<Box<dyn Error>> for ApiError {
+    fn from(error: Box<dyn Error>) -> Self {
+        ApiError::Internal(error.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::error::ApiError;
+    use std::time::Duration;
+    use serde_json::json;
+
+    #[test]
+    fn test_error_to_value() {
+        let error = ApiError::MissingField(String::from("users"));
+        assert_eq!(error.to_value(), json!({"code": 101, "message": "Missing 'users'", "field": "users"}));
+
+        assert_eq!(ApiError::InvalidCredentials.to_value(), json!({"code": 201, "message": "Invalid credentials"}));
+
+        let error = ApiError::RateLimited(Duration::from_millis(1500));
+        assert_eq!(error.to_value(), json!({"code": 400, "message": "Too many requests", "retryAfter": 2}));
+    }
+
+    #[test]
+    fn test_invalid_field() {
+        let error = ApiError::InvalidField{
+            field: String::from("users[2].publicKey"),
+            reason: String::from("must be base64"),
+        };
+
+        assert_eq!(error.within("requests[0]").to_value(), json!({
+            "code": 103,
+            "message": "'requests[0].users[2].publicKey' must be base64",
+            "field": "requests[0].users[2].publicKey",
+        }));
+    }
+
+    #[test]
+    fn test_internal_error_hidden() {
+        let error = ApiError::Internal(String::from("relation \"users\" does not exist"));
+        assert_eq!(error.message(), "Internal server error");
+        assert!(error.to_string().contains("relation"));
+
+        let error: ApiError = sqlx::Error::PoolTimedOut.into();
+        assert_eq!(error.code(), 500);
+        assert_eq!(ApiError::from(sqlx::Error::RowNotFound), ApiError::NotFound);
+    }
+}
@@ -0,0 +1,15 @@
+use crate::api;
+
+use serde::Serialize;
+
+/// A notification pushed to clients without them making a request
+///
+/// Events are formatted like responses, but named instead of having a status.
+#[derive(Serialize)]
+pub struct Event {
+    #[serde(rename = "event")]
+    pub name: String,
+    pub users: Option<Vec<api::User>>,
+    pub messages: Option<Vec<api::Message>>,
+    pub conversations: Option<Vec<api::Conversation>>,
+}
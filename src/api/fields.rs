@@ -0,0 +1,144 @@
+use std::error::Error;
+use std::fmt;
+use serde::de::{self, Deserializer, IntoDeserializer, Visitor};
+use serde::de::value::{MapDeserializer, SeqDeserializer};
+use serde::forward_to_deserialize_any;
+use serde_json::Value;
+
+/// Why the fields of a request could not be read, naming missing and unknown fields as serde reports them
+#[derive(Debug, PartialEq)]
+pub enum FieldError {
+    /// A field the object needs was not sent
+    Missing(&'static str),
+    /// A field was sent that the object does not have
+    Unknown(String),
+    /// A field was sent but could not be read, for the reason given
+    Invalid(String),
+}
+
+impl fmt::Display for FieldError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FieldError::Missing(field) => write!(f, "missing field `{}`", field),
+            FieldError::Unknown(field) => write!(f, "unknown field `{}`", field),
+            FieldError::Invalid(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl Error for FieldError {}
+
+impl de::Error for FieldError {
+    fn custom<T: fmt::Display>(message: T) -> Self {
+        FieldError::Invalid(message.to_string())
+    }
+
+    fn missing_field(field: &'static str) -> Self {
+        FieldError::Missing(field)
+    }
+
+    fn unknown_field(field: &str, _expected: &'static [&'static str]) -> Self {
+        FieldError::Unknown(field.to_owned())
+    }
+}
+
+/// Reads a parsed JSON value, reporting errors as field errors instead of serde_json's messages
+#[derive(Clone, Copy)]
+pub struct Fields<'a>(pub &'a Value);
+
+impl<'de> Deserializer<'de> for Fields<'de> {
+    type Error = FieldError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, FieldError> {
+        match self.0 {
+            Value::Null => visitor.visit_unit(),
+            Value::Bool(b) => visitor.visit_bool(*b),
+            Value::Number(n) => match (n.as_u64(), n.as_i64(), n.as_f64()) {
+                (Some(u), _, _) => visitor.visit_u64(u),
+                (_, Some(i), _) => visitor.visit_i64(i),
+                (_, _, f) => visitor.visit_f64(f.unwrap_or_default()),
+            },
+            Value::String(s) => visitor.visit_borrowed_str(s),
+            Value::Array(items) => {
+                let mut items = SeqDeserializer::new(items.iter().map(Fields));
+                let value = visitor.visit_seq(&mut items)?;
+                items.end()?;
+                Ok(value)
+            },
+            Value::Object(fields) => {
+                let mut fields = MapDeserializer::new(fields.iter().map(|(k, v)| (k.as_str(), Fields(v))));
+                let value = visitor.visit_map(&mut fields)?;
+                fields.end()?;
+                Ok(value)
+            },
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, FieldError> {
+        match self.0 {
+            Value::Null => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, FieldError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(self, _name: &'static str, _variants: &'static [&'static str], visitor: V) -> Result<V::Value, FieldError> {
+        // Only enums of unit variants, written as strings, are sent by clients
+        match self.0 {
+            Value::String(s) => visitor.visit_enum(s.as_str().into_deserializer()),
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+impl<'de> IntoDeserializer<'de, FieldError> for Fields<'de> {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::fields::{FieldError, Fields};
+    use std::collections::HashMap;
+    use serde::Deserialize;
+    use serde_json::json;
+
+    #[derive(Debug, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct Item {
+        id: i32,
+        name: Option<String>,
+    }
+
+    #[test]
+    fn test_field_errors() {
+        let json = json!({"id": 1, "name": null});
+        let item = Item::deserialize(Fields(&json)).unwrap();
+        assert_eq!((item.id, item.name), (1, None));
+
+        let json = json!({"name": "item"});
+        assert_eq!(Item::deserialize(Fields(&json)).unwrap_err(), FieldError::Missing("id"));
+
+        let json = json!({"id": 1, "nmae": "item"});
+        assert_eq!(Item::deserialize(Fields(&json)).unwrap_err(), FieldError::Unknown(String::from("nmae")));
+
+        let json = json!({"id": 4294967296u64});
+        let error = Item::deserialize(Fields(&json)).unwrap_err();
+        assert_eq!(error.to_string(), "invalid value: integer `4294967296`, expected i32");
+
+        let json = json!({"a": [1, 2], "b": []});
+        let lists = HashMap::<String, Vec<u8>>::deserialize(Fields(&json)).unwrap();
+        assert_eq!(lists["a"], [1, 2]);
+    }
+}
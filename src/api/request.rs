@@ -1,432 +1,875 @@
-use crate::api;
-use crate::auth::{Login, Password};
-use crate::api::ApiObject;
+use crate::auth::{Login, Password, Sessions};
+use crate::api::{Conversation, Message, Session, User, Validation};
+use crate::api::error::ApiError;
+use crate::api::fields::{FieldError, Fields};
+use crate::api::event::Event;
 use crate::api::response::Response;
-
-use std::error::Error;
-use std::io::Error as ioErr;
-use std::io::ErrorKind as ioErrKind;
-use api::{Conversation, Message, User};
+use crate::protocol::{Command, CreateConversations, CreateMessages, CreateUsers, NewMessage, NewUser};
+use crate::protocol::{ReadCommand, ReadMessages, ReadUsers, Request, RevokeSessions, SessionInfo, Subscription, Transaction, VerifySessions, VerifyUsers};
+use crate::registry::{Outbox, Registry};
+
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
 use serde_json::Value;
-use sqlx::PgPool;
-
-/// An action that a request wants to take
-#[derive(Debug, PartialEq)]
-pub enum Operation {
-    Create,
-    Read,
-    Update,
-    Delete,
-    Verify,
-}
+use serde_path_to_error::Segment;
+use sqlx::{Connection, PgConnection};
 
-/// An entity that a request wants to act on
-#[derive(Debug, PartialEq)]
-pub enum Target {
-    Conversations,
-    Messages,
-    Users,
-}
+/// The fields of a command that takes nothing but its function
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NoFields {}
 
-/// A request sent by a client
-#[derive(Debug)]
-pub struct Request {
-    pub operation: Operation,
-    pub target: Target,
-    users: Option<Vec<api::User>>,
-    messages: Option<Vec<api::Message>>,
-    conversations: Option<Vec<api::Conversation>>,
-}
+/// A field that could not be read, along with where it is within the request
+type PathError = serde_path_to_error::Error<FieldError>;
+
+/// The longest client name stored with a session
+const MAX_CLIENT_LENGTH: usize = 64;
 
 impl Request {
-    /// Separate operation and target from a space-delimited string
-    fn split_function(function: &str) -> Result<(String, String), Box<dyn Error>> {
-        let split_func: Vec<&str> = function
-            .split_ascii_whitespace()
-            .collect();
+    /// Create a request object from JSON
+    pub fn from_json(data: &str, validation: Validation) -> Result<Self, ApiError> {
+        let data: Value = serde_json::from_str(data)?;
+        Request::from_value(&data, validation)
+    }
 
-        if split_func.len() < 2 {
-            return Err(Box::new(ioErr::new(ioErrKind::InvalidInput, "Malformed function request")));
-        }
+    /// Create a request object from a parsed JSON value, naming the field that could not be read if any
+    pub fn from_value(data: &Value, validation: Validation) -> Result<Self, ApiError> {
+        let mut fields = data.as_object()
+            .ok_or_else(|| ApiError::InvalidRequest(String::from("Request must be a JSON object")))?
+            .clone();
 
-        Ok((split_func[0].to_owned(), split_func[1].to_owned()))
-    }
+        let function = match fields.remove("function") {
+            Some(Value::String(f)) => f,
+            _ => return Err(ApiError::InvalidRequest(String::from("Invalid request function"))),
+        };
 
-    /// Create a request object from JSON
-    pub fn from_json(data: &str) -> Result<Self, Box<dyn Error>> {
-        let data: Value = serde_json::from_str(data)?;
+        let id = fields.remove("id")
+            .filter(|id| !id.is_null());
 
-        let (operation, target) = Request::split_function(data["function"].as_str()
-            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Invalid request function"))?)?;
-
-        let request = Self{
-            operation: match operation.to_uppercase().as_ref() {
-                "VERIFY" => Operation::Verify,
-                "CREATE" => Operation::Create,
-                "READ" => Operation::Read,
-                "UPDATE" => Operation::Update,
-                "DELETE" => Operation::Delete,
-                _ => return Err(Box::new(ioErr::new(ioErrKind::InvalidInput, "Unknown request"))),
-            },
-            target: match target.to_uppercase().as_ref() {
-                "CONVERSATIONS" => Target::Conversations,
-                "MESSAGES" => Target::Messages,
-                "USERS" => Target::Users,
-                _ => return Err(Box::new(ioErr::new(ioErrKind::InvalidInput, "Unknown target"))),
-            },
-            users: match data["users"].as_array() {
-                Some(d) => {
-                    let users = d
-                        .iter()
-                        .flat_map(|item| api::User::from_json(item))
-                        .collect();
-                    Some(users)
-                },
-                None => None,
-            },
-            messages: match data["messages"].as_array() {
-                Some(d) => {
-                    let messages = d
-                        .iter()
-                        .flat_map(|item| api::Message::from_json(item))
-                        .collect();
-                    Some(messages)
-                },
-                None => None,
-            },
-            conversations: match data["conversations"].as_array() {
-                Some(d) => {
-                    let conversations = d
-                        .iter()
-                        .flat_map(|item| api::Conversation::from_json(item))
-                        .collect();
-                    Some(conversations)
-                },
-                None => None,
-            },
+        // Functions are not case sensitive
+        let function = function
+            .split_ascii_whitespace()
+            .collect::<Vec<&str>>()
+            .join(" ")
+            .to_uppercase();
+
+        let reader = RequestFields{
+            fields: Value::Object(fields),
+            validation,
         };
+        let command = Command::read(&function, reader)
+            .ok_or_else(|| ApiError::InvalidRequest(String::from("Unknown request")))??;
 
-        Ok(request)
+        Ok(Request{
+            id,
+            command,
+        })
     }
+}
 
-    /// Authenticate a user for the duration of the session
-    pub async fn verify_users(self, login: &mut Login, db_pool: &PgPool) -> Result<Response, Box<dyn Error>> {
-        // Read remote data
-        let users = self.users
-            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'users' list"))?;
-        let user = users[0].clone();
+/// The fields of a request other than its function and ID, checked as strictly as the client asked
+struct RequestFields {
+    fields: Value,
+    validation: Validation,
+}
 
-        let email = user.email
-            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'email' field for 'user'"))?;
-        let remote_pass = user.password
-            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'password' field for 'user'"))?;
+impl ReadCommand for RequestFields {
+    type Error = ApiError;
 
-        // Read local data
-        let stream = sqlx::query_file!("src/sql/verify-user.sql", email)
-            .fetch_one(db_pool)
-            .await?;
+    fn fields<T: DeserializeOwned>(self) -> Result<T, ApiError> {
+        read_fields(self.fields, self.validation)
+    }
 
-        let local_pass = Password{
-            hash: stream.pass,
-            salt: stream.salt
-        };
+    fn nothing(self) -> Result<(), ApiError> {
+        read_fields::<NoFields>(self.fields, self.validation).map(|_| ())
+    }
 
-        // Validate password
-        match local_pass.is_valid(&remote_pass)? {
-            true => login.authenticate(email),
-            false => return Err(Box::new(ioErr::new(ioErrKind::PermissionDenied, "Invalid password"))),
+    fn transaction(self) -> Result<Transaction, ApiError> {
+        Ok(Transaction{
+            requests: read_requests(&self.fields, self.validation)?,
+        })
+    }
+}
+
+/// Read the fields of a command
+fn read_fields<T: DeserializeOwned>(mut fields: Value, validation: Validation) -> Result<T, ApiError> {
+    loop {
+        let error = match serde_path_to_error::deserialize(Fields(&fields)) {
+            Ok(command) => return Ok(command),
+            Err(e) => e,
         };
 
-        Ok(Response{
-            status: 1,
-            conversations: None,
-            messages: None,
-            users: None,
-        })
+        // Older clients expect unknown fields, fields of the wrong type and malformed items to be skipped
+        if validation == Validation::Lenient && discard(&mut fields, error.path()).is_some() {
+            continue;
+        }
+
+        return Err(field_error(&error));
     }
+}
 
-    /// Add users to the database
-    pub async fn create_users(self, db_pool: &PgPool) -> Result<Response, Box<dyn Error>> {
-        // Authenticate user
-        let users = self.users
-            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'users' list"))?;
-
-        for user in users {
-            // Unpack request
-            let email = user.email
-                .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'email' field for 'user'"))?;
-            let password = user.password
-                .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'password' field for 'user'"))?;
-            let public_key = user.public_key
-                .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'public_key' field for 'user'"))?;
-
-            // Salt and hash password
-            let password = Password::hash(&password, Option::None)?;
-
-            // Store user data
-            sqlx::query_file!("src/sql/create-user.sql",
-                    email,
-                    public_key,
-                    password.hash,
-                    password.salt)
-                .execute(db_pool)
-                .await?;
+/// Remove the field or item that could not be read, or the object missing a field
+fn discard(fields: &mut Value, path: &serde_path_to_error::Path) -> Option<()> {
+    let segments: Vec<&Segment> = path.iter().collect();
+    let (last, parents) = segments.split_last()?;
+
+    let mut parent = fields;
+    for segment in parents {
+        parent = match segment {
+            Segment::Seq{index} => parent.get_mut(*index)?,
+            Segment::Map{key} => parent.get_mut(key)?,
+            _ => return None,
         };
+    }
 
-        Ok(Response{
-            status: 1,
-            conversations: None,
-            messages: None,
-            users: None,
-        })
+    match last {
+        Segment::Seq{index} => {
+            let items = parent.as_array_mut().filter(|i| *index < i.len())?;
+            items.remove(*index);
+        },
+        Segment::Map{key} => {
+            parent.as_object_mut()?.remove(key)?;
+        },
+        _ => return None,
     }
 
-    /// Add user's conversations to the database
-    pub async fn create_conversations(self, login: &Login, db_pool: &PgPool) -> Result<Response, Box<dyn Error>> {
-        // Authenticate user
-        if login.is_authenticated == false {
-            return Err(Box::new(ioErr::new(ioErrKind::PermissionDenied, "Not authenticated")));
+    Some(())
+}
+
+/// Describe a field that could not be read by its path within the request
+fn field_error(error: &PathError) -> ApiError {
+    let path = error.path().to_string();
+
+    match error.inner() {
+        // Missing fields are reported at the object that should hold them
+        FieldError::Missing(field) => ApiError::MissingField(match path.as_ref() {
+            "." => field.to_string(),
+            _ => format!("{}.{}", path, field),
+        }),
+        FieldError::Unknown(_) => ApiError::InvalidField{
+            field: path,
+            reason: String::from("is not a known field"),
+        },
+        FieldError::Invalid(reason) => ApiError::InvalidField{
+            field: path,
+            reason: reason.to_owned(),
+        },
+    }
+}
+
+/// Read the requests making up a transaction
+fn read_requests(fields: &Value, validation: Validation) -> Result<Vec<Request>, ApiError> {
+    if validation == Validation::Strict {
+        if let Some(field) = fields.as_object().and_then(|f| f.keys().find(|k| *k != "requests")) {
+            return Err(ApiError::InvalidField{
+                field: field.to_owned(),
+                reason: String::from("is not a known field"),
+            });
         }
+    }
 
-        // Unpack request
-        let users = self.users
-            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'users' list"))?;
-        let conversations = self.conversations
-            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'conversations' list"))?;
+    let requests = match fields.get("requests") {
+        Some(Value::Array(r)) => r,
+        Some(_) if validation == Validation::Strict => return Err(ApiError::InvalidField{
+            field: String::from("requests"),
+            reason: String::from("must be a list"),
+        }),
+        _ => return Err(ApiError::MissingField(String::from("requests"))),
+    };
+
+    requests
+        .iter()
+        .enumerate()
+        .map(|(i, item)| Request::from_value(item, validation)
+            .map_err(|e| e.within(&format!("requests[{}]", i))))
+        .collect()
+}
 
-        let conversation = conversations[0].clone();
+/// Authenticate a user for the duration of the session, issuing a token to resume it on later connections
+///
+/// Any session the connection was already logged in with is ended, as if the user had logged out first.
+pub async fn verify_users(command: VerifyUsers, login: &mut Login, sessions: &Sessions, client: Option<&str>, address: Option<IpAddr>, connection: Option<usize>, db: &mut PgConnection) -> Result<Response, ApiError> {
+    // Read remote data
+    let email = command.user.email;
+    let remote_pass = command.user.password;
+
+    // Read local data
+    let stream = sqlx::query_file!("src/sql/verify-user.sql", email)
+        .fetch_optional(&mut *db)
+        .await?
+        .ok_or(ApiError::InvalidCredentials)?;
+
+    let local_pass = Password{
+        hash: stream.pass,
+        salt: stream.salt
+    };
+
+    // Validate password
+    if !local_pass.is_valid(&remote_pass)? {
+        return Err(ApiError::InvalidCredentials);
+    }
 
-        let name = conversation.name
-            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'name' field for 'conversation'"))?;
+    // Forget sessions that can no longer be resumed
+    let now = now();
+    sqlx::query_file!("src/sql/delete-expired-sessions.sql", now)
+        .execute(&mut *db)
+        .await?;
+
+    // Record where the user logged in, so they can log out from elsewhere
+    let token = Sessions::new_token()?;
+    let expires = now + sessions.lifetime.as_secs() as i64;
+    let client: Option<String> = client.map(|c| c.chars().take(MAX_CLIENT_LENGTH).collect());
+    let session = sqlx::query_file!("src/sql/create-session.sql",
+            email,
+            Sessions::hash_token(&token),
+            client,
+            address.map(|a| a.to_string()),
+            now,
+            expires)
+        .fetch_one(&mut *db)
+        .await?;
+
+    end_session(login, sessions, connection, db).await?;
+    login.authenticate(email, session.id);
+
+    Ok(Response{
+        session: Some(SessionInfo{
+            id: session.id,
+            token: Some(token),
+            expires: expires as u64,
+        }),
+        ..Response::success()
+    })
+}
 
-        // Create conversation
-        sqlx::query_file!("src/sql/create-conversation-1.sql", name)
-            .execute(db_pool)
-            .await?;
+/// Authenticate a user with a token from an earlier login, without checking their password again
+///
+/// Any other session the connection was already logged in with is ended.
+pub async fn verify_sessions(command: VerifySessions, login: &mut Login, sessions: &Sessions, connection: Option<usize>, db: &mut PgConnection) -> Result<Response, ApiError> {
+    let previous = login.clone();
+    let session = resume_session(&command.session.token, login, db).await?;
 
-        // Add creator user
-        sqlx::query_file!("src/sql/create-conversation-2.sql", login.email, name)
-            .execute(db_pool)
-            .await?;
+    if previous.session != Some(session.id) {
+        end_session(&previous, sessions, connection, db).await?;
+    }
 
-        // Add remaining users
-        for user in users.clone() {
-            let email = user.email
-                .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'email' field for 'user'"))?;
+    Ok(Response{
+        session: Some(session),
+        ..Response::success()
+    })
+}
 
-            sqlx::query_file!("src/sql/create-conversation-2.sql", email, name)
-                .execute(db_pool)
-                .await?;
-        };
+/// Authenticate the user a session token was issued to, noting that the session is still in use
+pub async fn resume_session(token: &str, login: &mut Login, db: &mut PgConnection) -> Result<SessionInfo, ApiError> {
+    let session = sqlx::query_file!("src/sql/resume-session.sql", Sessions::hash_token(token), now())
+        .fetch_optional(&mut *db)
+        .await?
+        .ok_or(ApiError::InvalidCredentials)?;
 
-        Ok(Response{
-            status: 1,
-            conversations: None,
-            messages: None,
-            users: None,
-        })
+    login.authenticate(session.email, session.id);
+
+    Ok(SessionInfo{
+        id: session.id,
+        token: None,
+        expires: session.expires as u64,
+    })
+}
+
+/// Log a user out of the connection, ending their session everywhere it is in use
+pub async fn logout(login: &mut Login, sessions: &Sessions, connection: Option<usize>, db: &mut PgConnection) -> Result<Response, ApiError> {
+    // Authenticate user
+    if !login.is_authenticated {
+        return Err(ApiError::NotAuthenticated);
     }
 
-    /// Add messages from a conversation to the database
-    pub async fn create_messages(self, login: &Login, db_pool: &PgPool) -> Result<Response, Box<dyn Error>> {
-        // Authenticate user
-        if login.is_authenticated == false {
-            return Err(Box::new(ioErr::new(ioErrKind::PermissionDenied, "Not authenticated")));
-        }
+    end_session(login, sessions, connection, db).await?;
+    login.logout();
 
-        // Unpack request
-        let messages = self.messages
-            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'messages' list"))?;
-        let conversations = self.conversations
-            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'conversations' list"))?;
-        let conversation = &conversations[0];
-        let conversation_id = conversation.id
-            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'id' field for 'conversation'"))?;
-
-        for message in messages {
-            let data = message.data
-                .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'data' field for 'message'"))?;
-            let media_type = message.media_type
-                .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'media_type' field for 'message'"))?;
-            let timestamp = message.timestamp
-                .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'timestamp' field for 'message'"))?;
-            let signature = message.signature
-                .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'signature' field for 'message'"))?;
-
-            // Store user data
-            sqlx::query_file!("src/sql/create-message.sql",
-                    login.email,
-                    conversation_id,
-                    data,
-                    media_type,
-                    timestamp,
-                    signature)
-                .execute(db_pool)
-                .await?;
-        };
-        
-        Ok(Response{
-            status: 1,
-            conversations: None,
-            messages: None,
-            users: None,
+    Ok(Response::success())
+}
+
+/// End the session a connection is logged in with, closing any other connections that resumed it
+async fn end_session(login: &Login, sessions: &Sessions, connection: Option<usize>, db: &mut PgConnection) -> Result<(), ApiError> {
+    let session = match (login.session, login.is_authenticated) {
+        (Some(s), true) => s,
+        _ => return Ok(()),
+    };
+
+    // The token can no longer be resumed, even if another connection is using it
+    sqlx::query_file!("src/sql/delete-session.sql", login.email, session)
+        .fetch_optional(&mut *db)
+        .await?;
+
+    if let Some(connection) = connection {
+        sessions.detach(session, connection);
+    }
+    sessions.revoke(session);
+
+    Ok(())
+}
+
+/// List the sessions a user is logged in with, without their tokens
+pub async fn read_sessions(login: &Login, db: &mut PgConnection) -> Result<Response, ApiError> {
+    // Authenticate user
+    if !login.is_authenticated {
+        return Err(ApiError::NotAuthenticated);
+    }
+
+    // Read from database
+    let stream = sqlx::query_file!("src/sql/read-sessions.sql", login.email, now())
+        .fetch_all(&mut *db)
+        .await?;
+
+    // Format response
+    let sessions = stream
+        .into_iter()
+        .map(|s| Session{
+            id: s.id,
+            client: s.client,
+            address: s.address,
+            created: s.created,
+            last_seen: s.last_seen,
+            expires: s.expires,
+            current: login.session == Some(s.id),
         })
+        .collect();
+
+    Ok(Response{
+        sessions: Some(sessions),
+        ..Response::success()
+    })
+}
+
+/// Log out a user's listed sessions, or every session except the current one, closing connections that use them
+pub async fn revoke_sessions(command: RevokeSessions, login: &Login, sessions: &Sessions, db: &mut PgConnection) -> Result<Response, ApiError> {
+    // Authenticate user
+    if !login.is_authenticated {
+        return Err(ApiError::NotAuthenticated);
     }
 
-    /// Read a user's messages from the database
-    pub async fn read_conversations(self, login: &Login, db_pool: &PgPool) -> Result<Response, Box<dyn Error>> {
-        // Authenticate user
-        if login.is_authenticated == false {
-            return Err(Box::new(ioErr::new(ioErrKind::PermissionDenied, "Not authenticated")));
-        }
+    let mut tx = db.begin().await?;
+
+    // Only the user's own sessions can be revoked
+    let revoked = match command.sessions {
+        Some(ids) => {
+            let mut revoked = Vec::with_capacity(ids.len());
+            for session in ids {
+                let row = sqlx::query_file!("src/sql/delete-session.sql", login.email, session.id)
+                    .fetch_optional(&mut tx)
+                    .await?
+                    .ok_or(ApiError::NotFound)?;
+                revoked.push(row.id);
+            }
+            revoked
+        },
+        None => sqlx::query_file!("src/sql/delete-other-sessions.sql", login.email, login.session)
+            .fetch_all(&mut tx)
+            .await?
+            .into_iter()
+            .map(|row| row.id)
+            .collect(),
+    };
+
+    tx.commit().await?;
+
+    for session in revoked {
+        sessions.revoke(session);
+    }
 
-        // Read from database
-        let stream = sqlx::query_file!("src/sql/read-conversation.sql", login.email)
-            .fetch_all(db_pool)
-            .await?;
+    Ok(Response::success())
+}
 
-        // Format response
-        let conversations: Vec<Conversation> = stream
-            .iter()
-            .map(|c| Conversation{
-                id: Some(c.id),
-                name: Some(c.name.to_owned())
-            })
-            .collect();
+/// Find the current time in seconds since the Unix epoch
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
 
-        let response = Response{
-            status: 1,
-            conversations: Some(conversations),
-            messages: None,
-            users: None,
+/// Add users to the database, reporting whether each one was created
+pub async fn create_users(command: CreateUsers, db: &mut PgConnection) -> Result<Response, ApiError> {
+    let mut tx = db.begin().await?;
+
+    let mut results = Vec::with_capacity(command.users.len());
+    for user in command.users {
+        let result = match command.atomic {
+            true => Ok(create_user(user, &mut tx).await?),
+            // Undo only this user if it cannot be added
+            false => {
+                let mut item = tx.begin().await?;
+                match create_user(user, &mut item).await {
+                    Ok(id) => item.commit().await.map(|_| id).map_err(ApiError::from),
+                    Err(e) => Err(e),
+                }
+            },
         };
 
-        Ok(response)
+        results.push(result);
+    };
+
+    tx.commit().await?;
+
+    Ok(Response{
+        results: Some(results),
+        ..Response::success()
+    })
+}
+
+/// Add a single user to the database, returning their ID
+async fn create_user(user: NewUser, db: &mut PgConnection) -> Result<i32, ApiError> {
+    // Salt and hash password
+    let password = Password::hash(&user.password, Option::None)?;
+
+    // Store user data
+    let stream = sqlx::query_file!("src/sql/create-user.sql",
+            user.email,
+            user.public_key,
+            password.hash,
+            password.salt)
+        .fetch_one(&mut *db)
+        .await?;
+
+    Ok(stream.id)
+}
+
+/// Add user's conversations to the database and notify other participants
+pub async fn create_conversations(command: CreateConversations, login: &Login, db: &mut PgConnection, outbox: &mut Outbox) -> Result<Response, ApiError> {
+    // Authenticate user
+    if login.is_authenticated == false {
+        return Err(ApiError::NotAuthenticated);
     }
 
-    /// Read messages in a conversation from the database
-    pub async fn read_messages(self, login: &Login, db_pool: &PgPool) -> Result<Response, Box<dyn Error>> {
-        // Authenticate user
-        if login.is_authenticated == false {
-            return Err(Box::new(ioErr::new(ioErrKind::PermissionDenied, "Not authenticated")));
-        }
+    let name = command.conversation.name;
 
-        // Unpack request
-        let conversations = self.conversations
-            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'conversations' list"))?;
-        let conversation = &conversations[0];
+    // Create conversation, leaving nothing behind if any participant cannot be added
+    let mut tx = db.begin().await?;
 
-        let conversation_id = conversation.id
-            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'id' field for 'conversation'"))?;
+    let created = sqlx::query_file!("src/sql/create-conversation-1.sql", name)
+        .fetch_one(&mut *tx)
+        .await?;
 
-        // Read from database
-        let stream = sqlx::query_file!("src/sql/read-message.sql",
-                login.email,
-                conversation_id)
-            .fetch_all(db_pool)
+    // Add creator user
+    sqlx::query_file!("src/sql/create-conversation-2.sql", login.email, name)
+        .execute(&mut *tx)
+        .await?;
+
+    // Add remaining users
+    for user in command.users {
+        sqlx::query_file!("src/sql/create-conversation-2.sql", user.email, name)
+            .execute(&mut *tx)
             .await?;
+    };
+
+    // Tell other participants they have joined the conversation
+    let participants = read_participants(login, created.id, &mut tx).await?;
 
-        // Format response
-        let messages: Vec<Message> = stream
+    let event = Event{
+        name: String::from("CREATE CONVERSATIONS"),
+        users: Some(participants
             .iter()
-            .map(|m| Message{
-                id: None,
-                data: Some(m.data.to_owned()),
-                media_type: m.media_type.to_owned(),
-                timestamp: m.timestamp.to_owned(),
-                signature: m.signature.to_owned(),
-                sender: Some(m.email.to_owned()),
+            .map(|email| User{
+                email: Some(email.to_owned()),
+                name: None,
+                public_key: None,
             })
-            .collect();
+            .collect()),
+        messages: None,
+        conversations: Some(vec![Conversation{
+            id: Some(created.id),
+            name: Some(name),
+        }]),
+    };
+
+    tx.commit().await?;
+    outbox.push(created.id, participants, login.email.as_deref(), event);
+
+    Ok(Response::success())
+}
+
+/// Add user's messages to the database and notify other participants
+pub async fn create_messages(command: CreateMessages, login: &Login, db: &mut PgConnection, outbox: &mut Outbox) -> Result<Response, ApiError> {
+    // Authenticate user
+    if login.is_authenticated == false {
+        return Err(ApiError::NotAuthenticated);
+    }
+
+    let conversation_id = command.conversation.id;
 
-        let response = Response{
-            status: 1,
-            conversations: None,
-            messages: Some(messages),
+    // Check user participates in the conversation
+    let participants = read_participants(login, conversation_id, db).await?;
+    if participants.is_empty() {
+        return Err(ApiError::NotParticipant);
+    }
+
+    let mut tx = db.begin().await?;
+
+    let mut results = Vec::with_capacity(command.messages.len());
+    let mut stored = Vec::new();
+    for message in command.messages {
+        let result = match command.atomic {
+            true => Ok(create_message(message, login, conversation_id, &mut tx).await?),
+            // Undo only this message if it cannot be stored
+            false => {
+                let mut item = tx.begin().await?;
+                match create_message(message, login, conversation_id, &mut item).await {
+                    Ok(created) => item.commit().await.map(|_| created).map_err(ApiError::from),
+                    Err(e) => Err(e),
+                }
+            },
+        };
+
+        results.push(result.map(|(id, message)| {
+            stored.push(message);
+            id
+        }));
+    };
+
+    tx.commit().await?;
+
+    // Push new messages to other participants
+    if !stored.is_empty() {
+        let event = Event{
+            name: String::from("CREATE MESSAGES"),
             users: None,
+            messages: Some(stored),
+            conversations: Some(vec![Conversation{
+                id: Some(conversation_id),
+                name: None,
+            }]),
         };
 
-        Ok(response)
+        outbox.push(conversation_id, participants, login.email.as_deref(), event);
     }
 
-    /// Read users in a conversation from the database
-    pub async fn read_users(self, login: &Login, db_pool: &PgPool) -> Result<Response, Box<dyn Error>> {
-        // Authenticate user
-        if login.is_authenticated == false {
-            return Err(Box::new(ioErr::new(ioErrKind::PermissionDenied, "Not authenticated")));
-        }
+    Ok(Response{
+        results: Some(results),
+        ..Response::success()
+    })
+}
+
+/// Store a single message in a conversation, returning its ID and the message to send to other participants
+async fn create_message(message: NewMessage, login: &Login, conversation_id: i32, db: &mut PgConnection) -> Result<(i32, Message), ApiError> {
+    // Store message data
+    let stream = sqlx::query_file!("src/sql/create-message.sql",
+            login.email,
+            conversation_id,
+            message.data,
+            message.media_type,
+            message.timestamp,
+            message.signature)
+        .fetch_one(&mut *db)
+        .await?;
+
+    Ok((stream.id, Message{
+        data: Some(message.data),
+        media_type: Some(message.media_type),
+        timestamp: Some(message.timestamp),
+        signature: Some(message.signature),
+        sender: login.email.clone(),
+    }))
+}
 
-        // Unpack request
-        let conversations = self.conversations
-            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'conversations' list"))?;
-        let conversation = &conversations[0];
+/// Receive events on a connection for conversations the user participates in
+pub async fn subscribe_conversations(command: Subscription, login: &Login, db: &mut PgConnection, registry: &Registry, connection: usize) -> Result<Response, ApiError> {
+    // Authenticate user
+    if !login.is_authenticated {
+        return Err(ApiError::NotAuthenticated);
+    }
 
-        let conversation_id = conversation.id
-            .ok_or_else(|| ioErr::new(ioErrKind::InvalidInput, "Missing 'id' field for 'conversation'"))?;
+    // Subscribe to everything if no conversations are listed
+    let conversations = conversation_ids(command);
 
-        // Read from database
-        let stream = sqlx::query_file!("src/sql/read-user.sql",
+    // Check user participates in each conversation
+    for conversation_id in conversations.iter().flatten() {
+        sqlx::query_file!("src/sql/read-participant.sql",
                 login.email,
                 conversation_id)
-            .fetch_all(db_pool)
-            .await?;
+            .fetch_optional(&mut *db)
+            .await?
+            .ok_or(ApiError::NotParticipant)?;
+    }
 
-        // Format response
-        let users: Vec<User> = stream
-            .iter()
-            .map(|u| User{
-                id: None,
-                email: Some(u.email.to_owned()),
-                name: None,
-                password: None,
-                public_key: Some(u.public_key.to_owned()),
-            })
-            .collect();
+    let email = login.email.as_deref().unwrap_or_default();
+    if !registry.update_subscriptions(email, connection, |s| s.subscribe(conversations.as_deref())) {
+        return Err(ApiError::InvalidRequest(String::from("Connection cannot receive events")));
+    }
 
-        let response = Response{
-            status: 1,
-            conversations: None,
-            messages: None,
-            users: Some(users),
-        };
+    Ok(Response::success())
+}
+
+/// Stop receiving events on a connection for some conversations
+pub fn unsubscribe_conversations(command: Subscription, login: &Login, registry: &Registry, connection: usize) -> Result<Response, ApiError> {
+    // Authenticate user
+    if !login.is_authenticated {
+        return Err(ApiError::NotAuthenticated);
+    }
+
+    // Unsubscribe from everything if no conversations are listed
+    let conversations = conversation_ids(command);
+
+    let email = login.email.as_deref().unwrap_or_default();
+    if !registry.update_subscriptions(email, connection, |s| s.unsubscribe(conversations.as_deref())) {
+        return Err(ApiError::InvalidRequest(String::from("Connection cannot receive events")));
+    }
+
+    Ok(Response::success())
+}
+
+/// Collect the IDs of listed conversations, if any are listed
+fn conversation_ids(command: Subscription) -> Option<Vec<i32>> {
+    command.conversations.map(|conversations| conversations
+        .iter()
+        .map(|c| c.id)
+        .collect())
+}
+
+/// Read the emails of every participant in a conversation
+async fn read_participants(login: &Login, conversation_id: i32, db: &mut PgConnection) -> Result<Vec<String>, ApiError> {
+    let participants = sqlx::query_file!("src/sql/read-user.sql",
+            login.email,
+            conversation_id)
+        .fetch_all(&mut *db)
+        .await?
+        .into_iter()
+        .map(|p| p.email)
+        .collect();
+
+    Ok(participants)
+}
+
+/// Read a user's messages from the database
+pub async fn read_conversations(login: &Login, db: &mut PgConnection) -> Result<Response, ApiError> {
+    // Authenticate user
+    if login.is_authenticated == false {
+        return Err(ApiError::NotAuthenticated);
+    }
 
-        Ok(response)
+    // Read from database
+    let stream = sqlx::query_file!("src/sql/read-conversation.sql", login.email)
+        .fetch_all(&mut *db)
+        .await?;
+
+    // Format response
+    let conversations: Vec<Conversation> = stream
+        .iter()
+        .map(|c| Conversation{
+            id: Some(c.id),
+            name: Some(c.name.to_owned())
+        })
+        .collect();
+
+    let response = Response{
+        conversations: Some(conversations),
+        ..Response::success()
+    };
+
+    Ok(response)
+}
+
+/// Read messages in a conversation from the database
+pub async fn read_messages(command: ReadMessages, login: &Login, db: &mut PgConnection) -> Result<Response, ApiError> {
+    // Authenticate user
+    if login.is_authenticated == false {
+        return Err(ApiError::NotAuthenticated);
     }
+
+    // Read from database
+    let stream = sqlx::query_file!("src/sql/read-message.sql",
+            login.email,
+            command.conversation.id)
+        .fetch_all(&mut *db)
+        .await?;
+
+    // Format response
+    let messages: Vec<Message> = stream
+        .iter()
+        .map(|m| Message{
+            data: Some(m.data.to_owned()),
+            media_type: m.media_type.to_owned(),
+            timestamp: m.timestamp.to_owned(),
+            signature: m.signature.to_owned(),
+            sender: Some(m.email.to_owned()),
+        })
+        .collect();
+
+    let response = Response{
+        messages: Some(messages),
+        ..Response::success()
+    };
+
+    Ok(response)
+}
+
+/// Read users in a conversation from the database
+pub async fn read_users(command: ReadUsers, login: &Login, db: &mut PgConnection) -> Result<Response, ApiError> {
+    // Authenticate user
+    if login.is_authenticated == false {
+        return Err(ApiError::NotAuthenticated);
+    }
+
+    // Read from database
+    let stream = sqlx::query_file!("src/sql/read-user.sql",
+            login.email,
+            command.conversation.id)
+        .fetch_all(&mut *db)
+        .await?;
+
+    // Format response
+    let users: Vec<User> = stream
+        .iter()
+        .map(|u| User{
+            email: Some(u.email.to_owned()),
+            name: None,
+            public_key: Some(u.public_key.to_owned()),
+        })
+        .collect();
+
+    let response = Response{
+        users: Some(users),
+        ..Response::success()
+    };
+
+    Ok(response)
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::api::request::{Request, Operation, Target};
+    use crate::api::Validation;
+    use crate::api::error::ApiError;
+    use crate::protocol::{Command, Request};
     use serde_json::json;
 
     #[test]
     fn test_request_from_json() {
         let json = [
-            json!({"function": "CREATE USERS"}).to_string(),
-            json!({"function": "READ MESSAGES"}).to_string(),
-            json!({"function": "UPDATE CONVERSATIONS"}).to_string(),
-            json!({"function": "DELETE MESSAGES"}).to_string(),
-            json!({"function": "VERIFY USERS"}).to_string(),
+            json!({"function": "CREATE USERS", "users": []}).to_string(),
+            json!({"function": "read messages", "conversations": [{"id": 1}]}).to_string(),
+            json!({"function": "VERIFY USERS", "users": [{"email": "1@example.com", "password": "pass"}]}).to_string(),
+            json!({"function": "SUBSCRIBE CONVERSATIONS", "conversations": [{"id": 1}]}).to_string(),
+            json!({"function": "UNSUBSCRIBE  CONVERSATIONS"}).to_string(),
+            json!({"function": "PING"}).to_string(),
+            json!({"function": "TRANSACTION", "requests": [{"function": "READ CONVERSATIONS"}]}).to_string(),
+            json!({"function": "HELLO", "version": 1}).to_string(),
+            json!({"function": "verify sessions", "sessions": [{"token": "abc"}]}).to_string(),
+            json!({"function": "REVOKE SESSIONS", "sessions": [{"id": 3}]}).to_string(),
+            json!({"function": "LOGOUT"}).to_string(),
+        ];
+
+        let requests: Vec<Request> = json
+            .iter()
+            .map(|req| Request::from_json(&req, Validation::Strict).unwrap())
+            .collect();
+
+        assert!(matches!(&requests[0].command, Command::CreateUsers(c) if c.users.is_empty() && !c.atomic));
+        assert!(matches!(&requests[1].command, Command::ReadMessages(c) if c.conversation.id == 1));
+        assert!(matches!(&requests[2].command, Command::VerifyUsers(c) if c.user.email == "1@example.com"));
+        assert!(matches!(&requests[3].command, Command::SubscribeConversations(c) if c.conversations.is_some()));
+        assert!(matches!(&requests[4].command, Command::UnsubscribeConversations(c) if c.conversations.is_none()));
+        assert!(matches!(requests[5].command, Command::Ping));
+
+        match &requests[6].command {
+            Command::Transaction(t) => assert!(matches!(t.requests[0].command, Command::ReadConversations)),
+            c => panic!("Unexpected command {:?}", c),
+        }
+
+        assert!(matches!(&requests[7].command, Command::Hello(h) if h.version == 1));
+        assert!(matches!(&requests[8].command, Command::VerifySessions(c) if c.session.token == "abc"));
+        assert!(matches!(&requests[9].command, Command::RevokeSessions(c) if c.sessions.as_ref().unwrap()[0].id == 3));
+        assert!(matches!(requests[10].command, Command::Logout));
+    }
+
+    #[test]
+    fn test_malformed_function() {
+        assert!(Request::from_json(&json!({"function": "READ"}).to_string(), Validation::Strict).is_err());
+        assert!(Request::from_json(&json!({"function": "READ PINGS"}).to_string(), Validation::Strict).is_err());
+        assert!(Request::from_json(&json!({"function": "UPDATE CONVERSATIONS"}).to_string(), Validation::Strict).is_err());
+    }
+
+    #[test]
+    fn test_request_id_from_json() {
+        let json = [
+            json!({"id": 7, "function": "PING"}).to_string(),
+            json!({"id": "abc", "function": "PING"}).to_string(),
+            json!({"id": null, "function": "PING"}).to_string(),
+            json!({"function": "PING"}).to_string(),
         ];
 
         let requests: Vec<Request> = json
             .iter()
-            .map(|req| Request::from_json(&req).unwrap())
+            .map(|req| Request::from_json(&req, Validation::Strict).unwrap())
             .collect();
 
-        assert_eq!(requests[0].operation, Operation::Create);
-        assert_eq!(requests[0].target, Target::Users);
+        assert_eq!(requests[0].id, Some(json!(7)));
+        assert_eq!(requests[1].id, Some(json!("abc")));
+        assert_eq!(requests[2].id, None);
+        assert_eq!(requests[3].id, None);
+    }
 
-        assert_eq!(requests[1].operation, Operation::Read);
-        assert_eq!(requests[1].target, Target::Messages);
+    #[test]
+    fn test_request_validation() {
+        let invalid = [
+            (json!({"users": [{"email": 1, "password": "pass", "publicKey": "a2V5"}]}), "users[0].email"),
+            (json!({"users": [{"email": "1@example.com", "password": "pass", "publicKey": "not base64!"}]}), "users[0].publicKey"),
+            (json!({"users": [{"email": "1@example.com", "password": "pass", "publicKey": "a2V5", "emial": ""}]}), "users[0].emial"),
+            (json!({"users": {"email": "1@example.com"}}), "users"),
+            (json!({"users": [], "atomic": "yes"}), "atomic"),
+        ];
 
-        assert_eq!(requests[2].operation, Operation::Update);
-        assert_eq!(requests[2].target, Target::Conversations);
+        for (json, field) in invalid.iter() {
+            let mut json = json.clone();
+            json["function"] = json!("CREATE USERS");
 
-        assert_eq!(requests[3].operation, Operation::Delete);
-        assert_eq!(requests[3].target, Target::Messages);
+            let error = Request::from_value(&json, Validation::Strict).unwrap_err();
+            assert!(matches!(&error, ApiError::InvalidField{field: f, ..} if f == field), "{:?}", error);
+        }
+
+        let json = json!({
+            "function": "TRANSACTION",
+            "requests": [{"function": "READ USERS", "conversations": [{"id": 4294967296u64}]}],
+        });
+
+        let error = Request::from_value(&json, Validation::Strict).unwrap_err();
+        assert_eq!(error.to_value()["field"], "requests[0].conversations[0].id");
 
-        assert_eq!(requests[4].operation, Operation::Verify);
-        assert_eq!(requests[4].target, Target::Users);
+        let json = json!({"function": "CREATE USERS", "users": [{"email": "1@example.com", "password": "pass"}]});
+        let error = Request::from_value(&json, Validation::Strict).unwrap_err();
+        assert_eq!(error, ApiError::MissingField(String::from("users[0].publicKey")));
+
+        let json = json!({"function": "READ MESSAGES", "conversations": [{"id": 1}, {"id": 2}]});
+        let error = Request::from_value(&json, Validation::Strict).unwrap_err();
+        assert_eq!(error, ApiError::InvalidField{
+            field: String::from("conversations"),
+            reason: String::from("must hold exactly one item"),
+        });
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_lenient_validation() {
+        let json = json!({
+            "function": "CREATE USERS",
+            "users": [
+                {"email": "1@example.com", "password": "pass", "publicKey": "a2V5", "name": "Example User"},
+                {"email": "2@example.com", "password": "pass", "publicKey": "not base64!"},
+                "3@example.com",
+            ],
+            "atomic": "yes",
+        });
+
+        let request = Request::from_value(&json, Validation::Lenient).unwrap();
+        match request.command {
+            Command::CreateUsers(c) => {
+                assert_eq!(c.users.len(), 1);
+                assert_eq!(c.users[0].public_key, b"key");
+                assert!(!c.atomic);
+            },
+            c => panic!("Unexpected command {:?}", c),
+        }
+
+        let json = json!({"function": "READ USERS", "conversations": [{"id": "1"}]});
+        let error = Request::from_value(&json, Validation::Lenient).unwrap_err();
+        assert_eq!(error, ApiError::MissingField(String::from("conversations")));
+    }
+}
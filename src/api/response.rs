@@ -1,82 +1,76 @@
 use crate::api;
+use crate::api::error::ApiError;
+use crate::protocol::{ServerInfo, SessionInfo};
 
+use serde::{Serialize, Serializer};
 use serde_json::{Value, json};
 
 // A server response to a client's request
+#[derive(Serialize)]
 pub struct Response {
+    pub id: Option<Value>,
     pub status: u8,
     pub users: Option<Vec<api::User>>,
     pub messages: Option<Vec<api::Message>>,
     pub conversations: Option<Vec<api::Conversation>>,
+    pub error: Option<ApiError>,
+    /// Only bulk creation reports the outcome of each item
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "results")]
+    pub results: Option<Vec<Result<i32, ApiError>>>,
+    /// Only replies to HELLO describe the server
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server: Option<ServerInfo>,
+    /// Only transactions hold the responses to other requests
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub responses: Option<Vec<Response>>,
+    /// Only logging in issues a session to resume later
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session: Option<SessionInfo>,
+    /// Only listing sessions describes them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sessions: Option<Vec<api::Session>>,
 }
 
 impl Response {
-    /// Format response as JSON
-    pub fn to_json(&self) -> String {
-        let users = &self.users_to_json();
-        let messages = &self.messages_to_json();
-        let conversations = &self.conversations_to_json();
-
-        json!({
-            "status": &self.status,
-            "users": users,
-            "messages": messages,
-            "conversations": conversations,
-        }).to_string()
-    }
-
-    /// Format user array as JSON
-    fn users_to_json(&self) -> Option<Value> {
-        match &self.users {
-            Some(users) => {
-                Some(users
-                    .iter()
-                    .map(|user| json!({
-                        "email": user.email,
-                        "name": user.name,
-                        "publicKey": user.public_key,
-                    }))
-                    .collect()
-                )
-            },
-            None => None,
+    /// Create a successful response carrying nothing else, for handlers to fill in
+    pub fn success() -> Self {
+        Response{
+            id: None,
+            status: 1,
+            users: None,
+            messages: None,
+            conversations: None,
+            error: None,
+            results: None,
+            server: None,
+            responses: None,
+            session: None,
+            sessions: None,
         }
     }
 
-    /// Format message array as JSON
-    fn messages_to_json(&self) -> Option<Value> {
-        match &self.messages {
-            Some(messages) => {
-                Some(messages
-                    .iter()
-                    .map(|message| json!({
-                        "data": message.data,
-                        "mediaType": message.media_type,
-                        "timestamp": message.timestamp,
-                        "signature": message.signature,
-                        "sender": message.sender,
-                    }))
-                    .collect()
-                )
-            },
-            None => None,
+    /// Create a failure response explaining what went wrong
+    pub fn from_error(error: ApiError) -> Self {
+        Response{
+            status: 0,
+            error: Some(error),
+            ..Response::success()
         }
     }
 
-    /// Format conversation array as JSON
-    fn conversations_to_json(&self) -> Option<Value> {
-        match &self.conversations {
-            Some(conversations) => {
-                Some(conversations
-                    .iter()
-                    .map(|conversation| json!({
-                        "id": conversation.id,
-                        "name": conversation.name,
-                    }))
-                    .collect()
-                )
-            },
-            None => None,
-        }
+    /// Format response as a JSON value
+    pub fn to_value(&self) -> Value {
+        serde_json::to_value(self).unwrap_or_default()
     }
-}
\ No newline at end of file
+}
+
+/// Format the outcome of each item as a status with the item's ID or the reason it failed
+fn results<S: Serializer>(results: &Option<Vec<Result<i32, ApiError>>>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(results
+        .iter()
+        .flatten()
+        .map(|result| match result {
+            Ok(id) => json!({"status": 1, "id": id}),
+            Err(e) => json!({"status": 0, "error": e}),
+        }))
+}
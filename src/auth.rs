@@ -1,19 +1,100 @@
+use std::collections::HashMap;
 use std::error::Error;
 use std::str;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use async_std::channel::Sender;
 use argon2;
 use getrandom;
+use sha2::{Digest, Sha256};
 
 /// A user authenticated to use the current connection
+#[derive(Clone)]
 pub struct Login {
     pub email: Option<String>,
     pub is_authenticated: bool,
+    /// The stored session the user logged in with
+    pub session: Option<i32>,
 }
 
 impl Login {
-    /// Set a user as authenticated
-    pub fn authenticate(&mut self, email: String) {
+    /// Set a user as authenticated with a session
+    pub fn authenticate(&mut self, email: String, session: i32) {
         self.email = Some(email);
         self.is_authenticated = true;
+        self.session = Some(session);
+    }
+
+    /// Forget the user, leaving the connection unauthenticated
+    pub fn logout(&mut self) {
+        self.email = None;
+        self.is_authenticated = false;
+        self.session = None;
+    }
+}
+
+/// Channels closing each connection logged in with a session, keyed by session and then connection
+type Live = HashMap<i32, HashMap<usize, Sender<()>>>;
+
+/// Stored sessions that users are logged in with, along with the connections using each one
+#[derive(Clone)]
+pub struct Sessions {
+    /// How long a session can be resumed for after logging in
+    pub lifetime: Duration,
+    live: Arc<Mutex<Live>>,
+}
+
+impl Sessions {
+    /// Keep track of sessions that each last for some time after logging in
+    pub fn new(lifetime: Duration) -> Self {
+        Sessions{
+            lifetime,
+            live: Arc::default(),
+        }
+    }
+
+    /// Generate a token that is hard to guess for a new session
+    pub fn new_token() -> Result<String, Box<dyn Error>> {
+        let mut token = vec![0u8; 32];
+        getrandom::getrandom(&mut token)?;
+
+        Ok(base64::encode_config(&token, base64::URL_SAFE_NO_PAD))
+    }
+
+    /// Digest a session token for storing and looking up, so the token itself is never kept
+    pub fn hash_token(token: &str) -> Vec<u8> {
+        Sha256::digest(token.as_bytes()).to_vec()
+    }
+
+    /// Note that a connection is logged in with a session, which closes the channel if it is revoked
+    pub fn attach(&self, session: i32, connection: usize, revoke: Sender<()>) {
+        self.live
+            .lock()
+            .unwrap()
+            .entry(session)
+            .or_default()
+            .insert(connection, revoke);
+    }
+
+    /// Note that a connection is no longer logged in with a session
+    pub fn detach(&self, session: i32, connection: usize) {
+        let mut live = self.live.lock().unwrap();
+
+        if let Some(connections) = live.get_mut(&session) {
+            connections.remove(&connection);
+            if connections.is_empty() {
+                live.remove(&session);
+            }
+        }
+    }
+
+    /// Close every connection logged in with a session that has been revoked
+    pub fn revoke(&self, session: i32) {
+        if let Some(connections) = self.live.lock().unwrap().remove(&session) {
+            for revoke in connections.values() {
+                revoke.close();
+            }
+        }
     }
 }
 
@@ -60,7 +141,9 @@ impl Password {
 
 #[cfg(test)]
 mod tests {
-    use crate::auth::Password;
+    use crate::auth::{Password, Sessions};
+    use std::time::Duration;
+    use async_std::channel;
 
     #[test]
     fn test_hash() {
@@ -91,4 +174,30 @@ mod tests {
 
         assert_eq!(hash.is_valid(password).unwrap(), true);
     }
+
+    #[test]
+    fn test_sessions() {
+        let sessions = Sessions::new(Duration::from_secs(60));
+        assert_ne!(Sessions::new_token().unwrap(), Sessions::new_token().unwrap());
+
+        // Tokens are stored as their SHA-256 digest
+        let hash = Sessions::hash_token("abc");
+        assert_eq!(hash.len(), 32);
+        assert_eq!(&hash[..4], [0xba, 0x78, 0x16, 0xbf]);
+
+        let (first, first_revoked) = channel::bounded::<()>(1);
+        let (second, second_revoked) = channel::bounded::<()>(1);
+        sessions.attach(1, 10, first);
+        sessions.attach(2, 11, second.clone());
+
+        // Only connections using a revoked session are closed
+        sessions.revoke(1);
+        assert!(first_revoked.is_closed());
+        assert!(!second_revoked.is_closed());
+
+        // Connections that logged out are left alone
+        sessions.detach(2, 11);
+        sessions.revoke(2);
+        assert!(!second_revoked.is_closed());
+    }
 }
@@ -0,0 +1,137 @@
+use crate::api::error::ApiError;
+
+use std::io::{Read, Write};
+use std::str::FromStr;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+
+/// Marks a frame sent as it is
+const UNCOMPRESSED: u8 = 0;
+
+/// Marks a frame compressed with deflate
+const DEFLATED: u8 = 1;
+
+/// An algorithm used to compress frames
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Method {
+    /// Raw deflate, as described in RFC 1951
+    Deflate,
+}
+
+impl FromStr for Method {
+    type Err = ApiError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "deflate" => Ok(Method::Deflate),
+            _ => Err(ApiError::InvalidField{
+                field: String::from("compression"),
+                reason: String::from("is not a supported compression method"),
+            }),
+        }
+    }
+}
+
+impl Method {
+    /// Every compression method the server understands
+    pub const ALL: &'static [Method] = &[Method::Deflate];
+
+    /// Find the name clients use to ask for the method
+    pub fn name(&self) -> &'static str {
+        match self {
+            Method::Deflate => "deflate",
+        }
+    }
+}
+
+/// Compression applied to frames once a client asks for it, each frame starting with a byte saying whether it is compressed
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Compression {
+    pub method: Method,
+    /// Frames smaller than this many bytes are sent uncompressed, since compressing them saves little
+    pub threshold: usize,
+    /// The largest a frame may become once decompressed
+    pub max_size: usize,
+}
+
+impl Compression {
+    /// Compress a frame if it is large enough to be worth it
+    pub fn compress(&self, data: Vec<u8>) -> Vec<u8> {
+        if data.len() >= self.threshold {
+            let mut encoder = DeflateEncoder::new(vec![DEFLATED], flate2::Compression::default());
+
+            // Frames that do not shrink are sent as they are
+            if let Ok(compressed) = encoder.write_all(&data).and_then(|_| encoder.finish()) {
+                if compressed.len() <= data.len() {
+                    return compressed;
+                }
+            }
+        }
+
+        let mut frame = Vec::with_capacity(data.len() + 1);
+        frame.push(UNCOMPRESSED);
+        frame.extend_from_slice(&data);
+        frame
+    }
+
+    /// Read a frame that may have been compressed
+    pub fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, ApiError> {
+        match data.split_first() {
+            Some((&UNCOMPRESSED, data)) => Ok(data.to_vec()),
+            Some((&DEFLATED, data)) => {
+                // Stop reading requests that expand too far rather than holding them in memory
+                let mut decompressed = Vec::new();
+                DeflateDecoder::new(data)
+                    .take(self.max_size as u64 + 1)
+                    .read_to_end(&mut decompressed)
+                    .map_err(|_| ApiError::InvalidRequest(String::from("Request could not be decompressed")))?;
+
+                match decompressed.len() > self.max_size {
+                    true => Err(ApiError::InvalidRequest(String::from("Request exceeds maximum size once decompressed"))),
+                    false => Ok(decompressed),
+                }
+            },
+            _ => Err(ApiError::InvalidRequest(String::from("Request must start with a compression flag"))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compression::{Compression, Method};
+
+    const COMPRESSION: Compression = Compression{
+        method: Method::Deflate,
+        threshold: 16,
+        max_size: 1024,
+    };
+
+    #[test]
+    fn test_compress() {
+        let small = b"small".to_vec();
+        assert_eq!(COMPRESSION.compress(small.clone()), b"\0small".to_vec());
+        assert_eq!(COMPRESSION.decompress(b"\0small").unwrap(), small);
+
+        let large = b"abc".repeat(100);
+        let compressed = COMPRESSION.compress(large.clone());
+        assert_eq!(compressed[0], 1);
+        assert!(compressed.len() < large.len());
+        assert_eq!(COMPRESSION.decompress(&compressed).unwrap(), large);
+    }
+
+    #[test]
+    fn test_decompress_limits() {
+        let compressed = COMPRESSION.compress(vec![0; 2048]);
+        assert!(COMPRESSION.decompress(&compressed).is_err());
+
+        assert!(COMPRESSION.decompress(b"").is_err());
+        assert!(COMPRESSION.decompress(b"\x02data").is_err());
+        assert!(COMPRESSION.decompress(b"\x01not deflate").is_err());
+    }
+
+    #[test]
+    fn test_parse_method() {
+        assert_eq!("DEFLATE".parse::<Method>().unwrap(), Method::Deflate);
+        assert!("zstd".parse::<Method>().is_err());
+    }
+}
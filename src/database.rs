@@ -27,6 +27,9 @@ pub async fn init_db() -> Result<Pool<Postgres>, Box<dyn Error>> {
         create_tables(&pool).await?;
     }
 
+    // Bring databases set up by older versions up to date
+    migrate_tables(&pool).await?;
+
     Ok(pool)
 }
 
@@ -60,4 +63,23 @@ async fn create_tables(pool: &Pool<Postgres>) -> Result<(), Box<dyn Error>> {
 
     info!("New tables created");
     Ok(())
+}
+
+/// Create tables added since a database was first set up, leaving any that already exist as they are
+///
+/// Databases without a users table, such as ones just dropped without being created again, are left empty.
+async fn migrate_tables(pool: &Pool<Postgres>) -> Result<(), Box<dyn Error>> {
+    let users = sqlx::query_file!("src/sql/tables/exists-users.sql")
+        .fetch_one(pool)
+        .await?;
+
+    if !users.exists {
+        return Ok(());
+    }
+
+    sqlx::query_file!("src/sql/tables/sessions.sql")
+        .execute(pool)
+        .await?;
+
+    Ok(())
 }
\ No newline at end of file
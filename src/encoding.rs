@@ -0,0 +1,198 @@
+use crate::api::error::ApiError;
+use crate::compression::Compression;
+
+use std::str::{self, FromStr};
+use rmpv::Value as MsgValue;
+use serde::Serialize;
+use serde_json::{Map, Number, Value};
+
+/// A format that requests, responses and events are written in
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    /// Text, with byte fields sent as base64 in requests and as arrays of numbers in responses
+    Json,
+    /// MessagePack, with byte fields sent as raw binary
+    MessagePack,
+}
+
+impl FromStr for Encoding {
+    type Err = ApiError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "json" => Ok(Encoding::Json),
+            "msgpack" => Ok(Encoding::MessagePack),
+            _ => Err(ApiError::InvalidField{
+                field: String::from("encoding"),
+                reason: String::from("is not a supported encoding"),
+            }),
+        }
+    }
+}
+
+impl Encoding {
+    /// Every encoding the server understands
+    pub const ALL: &'static [Encoding] = &[Encoding::Json, Encoding::MessagePack];
+
+    /// Find the name clients use to ask for the encoding
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Json => "json",
+            Encoding::MessagePack => "msgpack",
+        }
+    }
+
+    /// Write a response or event
+    pub fn encode<T: Serialize>(&self, value: &T) -> Vec<u8> {
+        // Responses and events only hold types that every encoding supports
+        match self {
+            Encoding::Json => serde_json::to_vec(value).unwrap_or_default(),
+            Encoding::MessagePack => rmp_serde::to_vec_named(value).unwrap_or_default(),
+        }
+    }
+
+    /// Read a request, or a batch of requests, as JSON with any raw byte fields in base64
+    pub fn decode(&self, data: &[u8]) -> Result<Value, ApiError> {
+        match self {
+            Encoding::Json => Ok(serde_json::from_str(str::from_utf8(data)?)?),
+            Encoding::MessagePack => {
+                let mut remaining = data;
+                let value = rmpv::decode::read_value(&mut remaining)
+                    .map_err(|e| ApiError::InvalidRequest(format!("Invalid MessagePack: {}", e)))?;
+
+                if !remaining.is_empty() {
+                    return Err(ApiError::InvalidRequest(String::from("Invalid MessagePack: trailing data")));
+                }
+
+                to_json(value)
+            },
+        }
+    }
+}
+
+/// How a connection writes its frames, which clients may change when they introduce themselves
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Format {
+    pub encoding: Encoding,
+    pub compression: Option<Compression>,
+}
+
+impl Default for Format {
+    fn default() -> Self {
+        Format{
+            encoding: Encoding::Json,
+            compression: None,
+        }
+    }
+}
+
+impl Format {
+    /// Write a response or event as a frame
+    pub fn write<T: Serialize>(&self, value: &T) -> Vec<u8> {
+        let data = self.encoding.encode(value);
+
+        match &self.compression {
+            Some(c) => c.compress(data),
+            None => data,
+        }
+    }
+
+    /// Read a frame holding a request, or a batch of requests
+    pub fn read(&self, data: &[u8]) -> Result<Value, ApiError> {
+        match &self.compression {
+            Some(c) => self.encoding.decode(&c.decompress(data)?),
+            None => self.encoding.decode(data),
+        }
+    }
+}
+
+/// Convert a MessagePack value to the JSON that would have been sent instead
+fn to_json(value: MsgValue) -> Result<Value, ApiError> {
+    let invalid = |reason: &str| ApiError::InvalidRequest(format!("Invalid MessagePack: {}", reason));
+
+    let value = match value {
+        MsgValue::Nil => Value::Null,
+        MsgValue::Boolean(b) => Value::Bool(b),
+        MsgValue::Integer(i) => match (i.as_u64(), i.as_i64()) {
+            (Some(n), _) => Value::from(n),
+            (_, Some(n)) => Value::from(n),
+            _ => return Err(invalid("integer out of range")),
+        },
+        MsgValue::F32(f) => Number::from_f64(f as f64).map(Value::Number).ok_or_else(|| invalid("number out of range"))?,
+        MsgValue::F64(f) => Number::from_f64(f).map(Value::Number).ok_or_else(|| invalid("number out of range"))?,
+        MsgValue::String(s) => Value::String(s.into_str().ok_or_else(|| invalid("strings must be valid UTF-8"))?),
+        // Byte fields are read from base64 in JSON
+        MsgValue::Binary(data) => Value::String(base64::encode(data)),
+        MsgValue::Array(items) => Value::Array(items
+            .into_iter()
+            .map(to_json)
+            .collect::<Result<_, _>>()?),
+        MsgValue::Map(entries) => {
+            let mut map = Map::with_capacity(entries.len());
+            for (key, value) in entries {
+                let key = match key {
+                    MsgValue::String(s) => s.into_str().ok_or_else(|| invalid("keys must be valid UTF-8"))?,
+                    _ => return Err(invalid("keys must be strings")),
+                };
+                map.insert(key, to_json(value)?);
+            }
+            Value::Object(map)
+        },
+        MsgValue::Ext(..) => return Err(invalid("extension types are not supported")),
+    };
+
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::User;
+    use crate::encoding::Encoding;
+    use rmpv::Value as MsgValue;
+    use serde_json::json;
+
+    #[test]
+    fn test_decode_msgpack() {
+        let request = MsgValue::Map(vec![
+            (MsgValue::from("function"), MsgValue::from("CREATE USERS")),
+            (MsgValue::from("id"), MsgValue::from(-3)),
+            (MsgValue::from("users"), MsgValue::Array(vec![MsgValue::Map(vec![
+                (MsgValue::from("publicKey"), MsgValue::Binary(b"key".to_vec())),
+            ])])),
+        ]);
+        let mut data = Vec::new();
+        rmpv::encode::write_value(&mut data, &request).unwrap();
+
+        assert_eq!(Encoding::MessagePack.decode(&data).unwrap(), json!({
+            "function": "CREATE USERS",
+            "id": -3,
+            "users": [{"publicKey": "a2V5"}],
+        }));
+
+        data.push(0);
+        assert!(Encoding::MessagePack.decode(&data).is_err());
+        // Maps keyed by numbers
+        assert!(Encoding::MessagePack.decode(&[0x81, 0x01, 0x02]).is_err());
+    }
+
+    #[test]
+    fn test_encode() {
+        let user = User{
+            email: None,
+            name: None,
+            public_key: Some(b"ab".to_vec()),
+        };
+        assert_eq!(Encoding::Json.encode(&user), br#"{"email":null,"name":null,"publicKey":[97,98]}"#.to_vec());
+
+        let data = Encoding::MessagePack.encode(&user);
+        let value = rmpv::decode::read_value(&mut data.as_slice()).unwrap();
+        assert_eq!(value["publicKey"], MsgValue::Binary(b"ab".to_vec()));
+    }
+
+    #[test]
+    fn test_parse_encoding() {
+        assert_eq!("MsgPack".parse::<Encoding>().unwrap(), Encoding::MessagePack);
+        assert_eq!("json".parse::<Encoding>().unwrap(), Encoding::Json);
+        assert!("cbor".parse::<Encoding>().is_err());
+    }
+}
@@ -0,0 +1,258 @@
+use std::io::Error as ioErr;
+use std::io::ErrorKind as ioErrKind;
+use std::io::Result as ioResult;
+use std::str::FromStr;
+use async_std::io::{Read, ReadExt};
+use futures::stream::{self, Stream};
+use log::info;
+use serde::de::IgnoredAny;
+
+/// Number of bytes used by the length prefix of each frame
+const HEADER_SIZE: usize = 4;
+
+/// A method of separating requests and responses within a stream
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Framing {
+    /// Each frame is prefixed by its length as a 4-byte big-endian integer
+    LengthPrefixed,
+    /// Each frame is a single line terminated by a newline
+    Lines,
+}
+
+impl FromStr for Framing {
+    type Err = ioErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "length" => Ok(Framing::LengthPrefixed),
+            "lines" => Ok(Framing::Lines),
+            _ => Err(ioErr::new(ioErrKind::InvalidInput, "Unknown framing")),
+        }
+    }
+}
+
+/// A problem encountered while reading frames from a stream
+#[derive(Debug)]
+pub enum ReadError {
+    /// A request could not be read and the client should be told why
+    Invalid(String),
+    /// The stream can no longer be read and the connection should close
+    Closed(ioErr),
+}
+
+/// A buffer that splits a stream of bytes into frames
+pub struct FrameBuffer {
+    buffer: Vec<u8>,
+    framing: Framing,
+    max_size: usize,
+}
+
+impl FrameBuffer {
+    /// Create an empty buffer accepting frames up to a maximum size
+    pub fn new(framing: Framing, max_size: usize) -> Self {
+        FrameBuffer{
+            buffer: Vec::new(),
+            framing,
+            max_size,
+        }
+    }
+
+    /// Append data read from a stream to the buffer
+    pub fn extend(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Check if the buffer holds the start of an unfinished frame
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    /// Take the next complete frame from the buffer, if one is available
+    pub fn next_frame(&mut self) -> ioResult<Option<Vec<u8>>> {
+        match self.framing {
+            Framing::LengthPrefixed => self.next_prefixed_frame(),
+            Framing::Lines => self.next_line(),
+        }
+    }
+
+    /// Take the next length-prefixed frame from the buffer
+    fn next_prefixed_frame(&mut self) -> ioResult<Option<Vec<u8>>> {
+        if self.buffer.len() < HEADER_SIZE {
+            return Ok(None);
+        }
+
+        let mut header = [0u8; HEADER_SIZE];
+        header.copy_from_slice(&self.buffer[..HEADER_SIZE]);
+        let length = u32::from_be_bytes(header) as usize;
+
+        // Reject oversized frames as soon as the header arrives
+        if length > self.max_size {
+            return Err(ioErr::new(ioErrKind::InvalidData, "Frame exceeds maximum size"));
+        }
+
+        if self.buffer.len() < HEADER_SIZE + length {
+            return Ok(None);
+        }
+
+        let frame = self.buffer[HEADER_SIZE..HEADER_SIZE + length].to_vec();
+        self.buffer.drain(..HEADER_SIZE + length);
+
+        Ok(Some(frame))
+    }
+
+    /// Take the next non-empty newline-terminated frame from the buffer
+    fn next_line(&mut self) -> ioResult<Option<Vec<u8>>> {
+        loop {
+            let end = match self.buffer.iter().position(|&b| b == b'\n') {
+                Some(i) => i,
+                None if self.buffer.len() > self.max_size => {
+                    return Err(ioErr::new(ioErrKind::InvalidData, "Frame exceeds maximum size"));
+                },
+                None => return Ok(None),
+            };
+
+            if end > self.max_size {
+                return Err(ioErr::new(ioErrKind::InvalidData, "Frame exceeds maximum size"));
+            }
+
+            let mut line: Vec<u8> = self.buffer.drain(..=end).collect();
+            line.pop();
+
+            // Accept lines ending in CRLF from terminal clients
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+
+            // Skip blank lines between requests
+            if !line.iter().all(u8::is_ascii_whitespace) {
+                return Ok(Some(line));
+            }
+        }
+    }
+}
+
+/// Read frames from a stream until it ends or can no longer be split into frames
+///
+/// Problems with individual frames are returned as messages to report to the client,
+/// while errors that leave the stream unreadable end it.
+pub fn read_frames<R>(reader: R, framing: Framing, max_size: usize) -> impl Stream<Item = Result<Vec<u8>, ReadError>>
+where
+    R: Read + Unpin,
+{
+    let frames = FrameBuffer::new(framing, max_size);
+
+    stream::unfold(Some((reader, frames)), move |state| async move {
+        let (mut reader, mut frames) = state?;
+        let mut buffer = [0; 1024];
+
+        loop {
+            match frames.next_frame() {
+                Ok(Some(data)) => {
+                    // Lines must each hold a complete request
+                    if framing == Framing::Lines && serde_json::from_slice::<IgnoredAny>(&data).is_err() {
+                        let message = String::from("Request must be a single line of JSON");
+                        return Some((Err(ReadError::Invalid(message)), Some((reader, frames))));
+                    }
+
+                    return Some((Ok(data), Some((reader, frames))));
+                },
+                Ok(None) => {},
+                // The rest of the stream cannot be framed reliably
+                Err(e) => return Some((Err(ReadError::Invalid(e.to_string())), None)),
+            }
+
+            match reader.read(&mut buffer).await {
+                Ok(0) => {
+                    // Let the client know if its final request was cut short
+                    if !frames.is_empty() {
+                        info!("Discarded incomplete frame");
+                        return Some((Err(ReadError::Invalid(String::from("Incomplete request"))), None));
+                    }
+                    return None;
+                },
+                Ok(n) => frames.extend(&buffer[..n]),
+                Err(e) if e.kind() == ioErrKind::Interrupted => {},
+                Err(e) => return Some((Err(ReadError::Closed(e)), None)),
+            }
+        }
+    })
+}
+
+/// Wrap data so that it can be sent as a single frame
+pub fn encode(framing: Framing, data: &[u8]) -> Vec<u8> {
+    match framing {
+        Framing::LengthPrefixed => {
+            let mut frame = Vec::with_capacity(HEADER_SIZE + data.len());
+            frame.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            frame.extend_from_slice(data);
+            frame
+        },
+        Framing::Lines => {
+            let mut frame = Vec::with_capacity(data.len() + 1);
+            frame.extend_from_slice(data);
+            frame.push(b'\n');
+            frame
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::frame::{Framing, FrameBuffer, encode};
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(encode(Framing::LengthPrefixed, b"abc"), vec![0, 0, 0, 3, b'a', b'b', b'c']);
+        assert_eq!(encode(Framing::LengthPrefixed, b""), vec![0, 0, 0, 0]);
+        assert_eq!(encode(Framing::Lines, b"abc"), b"abc\n".to_vec());
+    }
+
+    #[test]
+    fn test_next_frame() {
+        let mut frames = FrameBuffer::new(Framing::LengthPrefixed, 16);
+        let mut data = encode(Framing::LengthPrefixed, b"first");
+        data.extend(encode(Framing::LengthPrefixed, b"second"));
+
+        // Deliver the data in two uneven chunks
+        frames.extend(&data[..7]);
+        assert_eq!(frames.next_frame().unwrap(), None);
+
+        frames.extend(&data[7..]);
+        assert_eq!(frames.next_frame().unwrap(), Some(b"first".to_vec()));
+        assert_eq!(frames.next_frame().unwrap(), Some(b"second".to_vec()));
+        assert_eq!(frames.next_frame().unwrap(), None);
+        assert!(frames.is_empty());
+    }
+
+    #[test]
+    fn test_next_line() {
+        let mut frames = FrameBuffer::new(Framing::Lines, 16);
+
+        frames.extend(b"{\"a\":1}\r\n\n{\"b\"");
+        assert_eq!(frames.next_frame().unwrap(), Some(b"{\"a\":1}".to_vec()));
+        assert_eq!(frames.next_frame().unwrap(), None);
+        assert!(!frames.is_empty());
+
+        frames.extend(b":2}\n");
+        assert_eq!(frames.next_frame().unwrap(), Some(b"{\"b\":2}".to_vec()));
+        assert!(frames.is_empty());
+    }
+
+    #[test]
+    fn test_oversized_frame() {
+        let mut frames = FrameBuffer::new(Framing::LengthPrefixed, 4);
+        frames.extend(&[0, 0, 0, 5]);
+        assert!(frames.next_frame().is_err());
+
+        let mut lines = FrameBuffer::new(Framing::Lines, 4);
+        lines.extend(b"12345");
+        assert!(lines.next_frame().is_err());
+    }
+
+    #[test]
+    fn test_framing_from_str() {
+        assert_eq!("length".parse::<Framing>().unwrap(), Framing::LengthPrefixed);
+        assert_eq!("LINES".parse::<Framing>().unwrap(), Framing::Lines);
+        assert!("xml".parse::<Framing>().is_err());
+    }
+}
@@ -0,0 +1,273 @@
+use crate::{Origin, Server};
+use crate::api::error::ApiError;
+use crate::api::request;
+use crate::auth;
+use crate::listener::Peer;
+use crate::shutdown::Tracker;
+
+use std::error::Error;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use async_std::io::{Read, Write};
+use http_types::{Body, Method, Request, Response, StatusCode};
+use futures::FutureExt;
+use http_types::headers::{AUTHORIZATION, CONNECTION, RETRY_AFTER, USER_AGENT};
+use log::error;
+use serde_json::{Value, json};
+
+/// A stream shared between the reading and writing halves of an HTTP connection
+struct SharedStream<S>(Arc<Mutex<S>>);
+
+impl<S> Clone for SharedStream<S> {
+    fn clone(&self) -> Self {
+        SharedStream(self.0.clone())
+    }
+}
+
+impl<S: Read + Unpin> Read for SharedStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut *self.0.lock().unwrap()).poll_read(cx, buf)
+    }
+}
+
+impl<S: Write + Unpin> Write for SharedStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut *self.0.lock().unwrap()).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.0.lock().unwrap()).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.0.lock().unwrap()).poll_close(cx)
+    }
+}
+
+/// Serve HTTP requests from a client over a stream
+pub async fn handle_stream<S>(stream: S, peer: Peer, server: &Server) -> Result<(), Box<dyn Error>>
+where
+    S: Read + Write + Send + Unpin + 'static,
+{
+    let stream = SharedStream(Arc::new(Mutex::new(stream)));
+    let requests = Tracker::default();
+
+    let accepting = async_h1::accept(stream, |req| {
+        let request = requests.track();
+        handle_request(req, peer, server).map(move |response| {
+            drop(request);
+            response
+        })
+    }).fuse();
+
+    // Close the connection between requests once the server starts shutting down
+    let shutdown = async {
+        server.wait_for_shutdown().await;
+        requests.wait_idle().await;
+    }.fuse();
+
+    futures::pin_mut!(accepting, shutdown);
+    futures::select! {
+        result = accepting => result.map_err(|e| e.into_inner())?,
+        _ = shutdown => {},
+    };
+
+    Ok(())
+}
+
+/// Answer a client's first request with an error and close the connection, without serving anything
+pub async fn refuse_stream<S>(stream: S, error: ApiError) -> Result<(), Box<dyn Error>>
+where
+    S: Read + Write + Send + Unpin + 'static,
+{
+    let stream = SharedStream(Arc::new(Mutex::new(stream)));
+
+    async_h1::accept(stream, |_| {
+        let error = error.clone();
+        async move {
+            let mut response = format_error(error_status(&error), error)?;
+            response.insert_header(CONNECTION, "close");
+            Ok(response)
+        }
+    }).await.map_err(|e| e.into_inner())?;
+
+    Ok(())
+}
+
+/// Find the function and conversation that a route refers to
+fn route(method: Method, path: &str) -> Option<(&'static str, Option<i32>)> {
+    let segments: Vec<&str> = path
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .collect();
+
+    match (method, segments.as_slice()) {
+        (Method::Post, ["users"]) => Some(("CREATE USERS", None)),
+        (Method::Post, ["sessions"]) => Some(("VERIFY USERS", None)),
+        (Method::Get, ["sessions"]) => Some(("READ SESSIONS", None)),
+        (Method::Delete, ["sessions"]) => Some(("REVOKE SESSIONS", None)),
+        (Method::Delete, ["sessions", "current"]) => Some(("LOGOUT", None)),
+        (Method::Get, ["conversations"]) => Some(("READ CONVERSATIONS", None)),
+        (Method::Post, ["conversations"]) => Some(("CREATE CONVERSATIONS", None)),
+        (Method::Get, ["conversations", id, "messages"]) => Some(("READ MESSAGES", Some(id.parse().ok()?))),
+        (Method::Post, ["conversations", id, "messages"]) => Some(("CREATE MESSAGES", Some(id.parse().ok()?))),
+        (Method::Get, ["conversations", id, "users"]) => Some(("READ USERS", Some(id.parse().ok()?))),
+        _ => None,
+    }
+}
+
+/// Translate an HTTP request into a client request and respond with the result
+async fn handle_request(mut req: Request, peer: Peer, server: &Server) -> http_types::Result<Response> {
+    let (function, conversation) = match route(req.method(), req.url().path()) {
+        Some(r) => r,
+        None => return format_error(StatusCode::NotFound, ApiError::InvalidRequest(String::from("Unknown route"))),
+    };
+
+    // Authenticate user with a bearer token
+    let mut login = auth::Login{
+        email: None,
+        is_authenticated: false,
+        session: None,
+    };
+
+    if let Some(header) = req.header(AUTHORIZATION) {
+        if let Err(e) = authenticate(header.as_str(), &mut login, server).await {
+            return format_error(error_status(&e), e);
+        }
+    }
+
+    // Sessions record which client logged in
+    let origin = Origin{
+        peer,
+        client: req.header(USER_AGENT).map(|h| h.as_str().to_owned()),
+        connection: None,
+    };
+
+    // Combine route and body into a single request
+    let body = req.body_string().await?;
+    let mut data: Value = match body.trim().is_empty() {
+        true => json!({}),
+        false => match serde_json::from_str(&body) {
+            Ok(Value::Object(d)) => Value::Object(d),
+            _ => return format_error(StatusCode::BadRequest, ApiError::InvalidRequest(String::from("Request body must be a JSON object"))),
+        },
+    };
+
+    data["function"] = json!(function);
+    if let Some(id) = conversation {
+        data["conversations"] = json!([{ "id": id }]);
+    }
+
+    let result = crate::handle_request(data.to_string().as_bytes(), &mut login, server, &origin).await;
+    let response = match result {
+        Ok(r) => r,
+        Err(e) => {
+            error!("{}", e);
+            return format_error(error_status(&e), e);
+        },
+    };
+
+    // Hand over the session token issued once a user has logged in
+    if let Some(session) = response.session {
+        return format_body(StatusCode::Created, json!({ "token": session.token, "expires": session.expires }));
+    }
+
+    let status = match req.method() {
+        Method::Post => StatusCode::Created,
+        _ => StatusCode::Ok,
+    };
+
+    format_body(status, success_body(&response))
+}
+
+/// Keep only what a request returned, since success is reported using the status code and nothing needs matching to a request ID
+fn success_body(response: &crate::api::response::Response) -> Value {
+    let mut body = response.to_value();
+    if let Some(b) = body.as_object_mut() {
+        b.retain(|key, value| !value.is_null() && !matches!(key.as_str(), "id" | "status" | "error"));
+    }
+    body
+}
+
+/// Log in with the session token in an authorization header
+async fn authenticate(header: &str, login: &mut auth::Login, server: &Server) -> Result<(), ApiError> {
+    let token = header
+        .strip_prefix("Bearer ")
+        .ok_or(ApiError::InvalidCredentials)?;
+
+    let mut db = server.acquire().await?;
+    request::resume_session(token, login, &mut db).await?;
+    Ok(())
+}
+
+/// Choose an HTTP status code describing why a request failed
+fn error_status(error: &ApiError) -> StatusCode {
+    match error {
+        ApiError::InvalidRequest(_) | ApiError::MissingField(_) | ApiError::InvalidField{..} => StatusCode::BadRequest,
+        ApiError::UnsupportedVersion => StatusCode::BadRequest,
+        ApiError::TimedOut(_) => StatusCode::RequestTimeout,
+        ApiError::NotAuthenticated | ApiError::InvalidCredentials => StatusCode::Unauthorized,
+        ApiError::NotParticipant => StatusCode::Forbidden,
+        ApiError::NotFound => StatusCode::NotFound,
+        ApiError::AlreadyExists => StatusCode::Conflict,
+        ApiError::RateLimited(_) => StatusCode::TooManyRequests,
+        ApiError::ServerBusy => StatusCode::ServiceUnavailable,
+        ApiError::Internal(_) => StatusCode::InternalServerError,
+    }
+}
+
+/// Create an HTTP response with a JSON body
+fn format_body(status: StatusCode, body: Value) -> http_types::Result<Response> {
+    let mut response = Response::new(status);
+    response.set_body(Body::from_json(&body)?);
+    Ok(response)
+}
+
+/// Create an HTTP response explaining what went wrong
+fn format_error(status: StatusCode, error: ApiError) -> http_types::Result<Response> {
+    let mut response = format_body(status, json!({ "error": error.to_value() }))?;
+
+    if let Some(seconds) = error.retry_after() {
+        response.insert_header(RETRY_AFTER, seconds.to_string());
+    }
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::Conversation;
+    use crate::api::response::Response;
+    use crate::http::{route, success_body};
+    use http_types::Method;
+    use serde_json::json;
+
+    #[test]
+    fn test_route() {
+        assert_eq!(route(Method::Post, "/users"), Some(("CREATE USERS", None)));
+        assert_eq!(route(Method::Post, "/sessions"), Some(("VERIFY USERS", None)));
+        assert_eq!(route(Method::Delete, "/sessions"), Some(("REVOKE SESSIONS", None)));
+        assert_eq!(route(Method::Delete, "/sessions/current"), Some(("LOGOUT", None)));
+        assert_eq!(route(Method::Get, "/conversations/"), Some(("READ CONVERSATIONS", None)));
+        assert_eq!(route(Method::Post, "/conversations"), Some(("CREATE CONVERSATIONS", None)));
+        assert_eq!(route(Method::Get, "/conversations/4/messages"), Some(("READ MESSAGES", Some(4))));
+        assert_eq!(route(Method::Post, "/conversations/4/messages"), Some(("CREATE MESSAGES", Some(4))));
+        assert_eq!(route(Method::Get, "/conversations/4/users"), Some(("READ USERS", Some(4))));
+
+        assert_eq!(route(Method::Delete, "/users"), None);
+        assert_eq!(route(Method::Get, "/conversations/abc/users"), None);
+        assert_eq!(route(Method::Get, "/unknown"), None);
+    }
+
+    #[test]
+    fn test_success_body() {
+        let response = Response{
+            id: Some(json!(7)),
+            conversations: Some(vec![Conversation{ id: Some(4), name: None }]),
+            ..Response::success()
+        };
+        assert_eq!(success_body(&response), json!({"conversations": [{"id": 4, "name": null}]}));
+        assert_eq!(success_body(&Response::success()), json!({}));
+    }
+}
@@ -1,118 +1,926 @@
 pub mod database;
+pub mod frame;
+pub mod listener;
+pub mod protocol;
 pub mod tls;
 mod api;
 mod auth;
+mod compression;
+mod encoding;
+mod http;
+mod limiter;
+mod proxy;
+mod registry;
 mod settings;
+mod shutdown;
+mod websocket;
 
-use crate::api::request::{Request, Operation, Target};
+use crate::api::Validation;
+use crate::api::error::ApiError;
+use crate::api::event::Event;
+use crate::api::request;
 use crate::api::response::Response;
+use crate::compression::Compression;
+use crate::encoding::{Encoding, Format};
+use crate::frame::{Framing, ReadError};
+use crate::limiter::{DEFAULT_LIMITS, ConnectionLimiter, RateLimiter};
+use crate::listener::Peer;
+use crate::protocol::{Command, Hello, Request, ServerInfo};
+use crate::registry::{Outbox, Registry};
+use crate::shutdown::Shutdown;
 //use crate::auth;
 
 use std::error::Error;
 use std::io::Error as ioErr;
 use std::io::ErrorKind as ioErrKind;
-use std::str;
-use std::time;
-use async_std::task;
+use std::str::{self, FromStr};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use async_std::channel::{self, Receiver, SendError, Sender};
+use async_std::io;
 use async_std::prelude::*;
-use async_std::net::TcpStream;
+use async_std::task;
+use async_std::io::{Read, Write};
 use async_tls::TlsAcceptor;
+use futures::{future, AsyncReadExt, FutureExt};
+use futures::stream::{FuturesUnordered, Stream, StreamExt};
 use log::{error, info};
-use sqlx::PgPool;
-
-/// Handle incoming connections from clients
-pub async fn handle_connection(stream: TcpStream, acceptor: &TlsAcceptor, db_pool: &PgPool) -> Result<(), Box<dyn Error>> {
-    let mut buffer = [0; 1024];
-    let interval = time::Duration::from_millis(500);
-    let address = stream.peer_addr()?;
-    let mut user = auth::Login{
-        email: None,
-        is_authenticated: false,
+use serde_json::Value;
+use sqlx::{PgConnection, PgPool, Postgres};
+use sqlx::pool::PoolConnection;
+
+/// Features of the protocol that clients can rely on, listed in reply to HELLO
+const CAPABILITIES: &[&str] = &["push", "pipelining", "batching", "transactions", "subscriptions", "compression"];
+
+/// A protocol used to exchange requests and responses with clients
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Transport {
+    /// Frames sent directly over the TLS stream
+    Stream(Framing),
+    /// Messages sent over a WebSocket
+    WebSocket,
+    /// Requests mapped from HTTP routes
+    Http,
+}
+
+/// Resources shared between every connection to the server
+#[derive(Clone)]
+pub struct Server {
+    db_pool: PgPool,
+    sessions: auth::Sessions,
+    registry: Registry,
+    limiter: RateLimiter,
+    connections: ConnectionLimiter,
+    busy_timeout: Duration,
+    shutdown: Shutdown,
+}
+
+impl Server {
+    /// Create a server that stores its data in a database, limiting requests and connections as configured
+    pub fn new(db_pool: PgPool) -> Result<Self, Box<dyn Error>> {
+        let limits = settings::get_or("RATE_LIMITS", DEFAULT_LIMITS.parse()?)?;
+        let max_connections = settings::get_or("MAX_CONNECTIONS", 10000)?;
+        let max_connections_per_ip = settings::get_or("MAX_CONNECTIONS_PER_IP", 100)?;
+
+        Ok(Server{
+            db_pool,
+            sessions: auth::Sessions::new(Duration::from_secs(settings::get_or("SESSION_LIFETIME", 2592000)?)),
+            registry: Registry::default(),
+            limiter: RateLimiter::new(limits),
+            connections: ConnectionLimiter::new(max_connections, max_connections_per_ip),
+            busy_timeout: Duration::from_millis(settings::get_or("BUSY_TIMEOUT", 1000)?),
+            shutdown: Shutdown::default(),
+        })
+    }
+
+    /// Stop accepting connections and tell open ones to finish, returning false if already stopping
+    pub fn shutdown(&self) -> bool {
+        self.shutdown.start()
+    }
+
+    /// Borrow a database connection, or turn the request away if none comes free in time
+    async fn acquire(&self) -> Result<PoolConnection<Postgres>, ApiError> {
+        async_std::future::timeout(self.busy_timeout, self.db_pool.acquire()).await
+            .map_err(|_| ApiError::ServerBusy)?
+            .map_err(ApiError::from)
+    }
+
+    /// Wait until the server starts shutting down
+    pub async fn wait_for_shutdown(&self) {
+        self.shutdown.wait().await
+    }
+
+    /// Wait for open connections to finish until a deadline, then disconnect from the database
+    pub async fn close(&self, deadline: Duration) {
+        if !self.shutdown.drain(deadline).await {
+            info!("Closing connections that did not finish in time");
+        }
+
+        self.db_pool.close().await;
+    }
+}
+
+/// A client's connection to the server
+struct Connection<'a> {
+    id: usize,
+    user: Mutex<auth::Login>,
+    sender: Sender<Vec<u8>>,
+    format: Mutex<Format>,
+    /// How strictly requests are checked, which older clients may relax when they introduce themselves
+    validation: Mutex<Validation>,
+    transport: Transport,
+    peer: Peer,
+    /// The name the client gave when it introduced itself, if any
+    client: Mutex<Option<String>>,
+    /// Closed when the session the user logged in with is revoked, or the client falls behind on events
+    revoke: Sender<()>,
+    revoked: Receiver<()>,
+    server: &'a Server,
+}
+
+impl<'a> Connection<'a> {
+    /// Create a connection that sends responses and events through a channel, as uncompressed JSON until a client asks otherwise
+    fn new(server: &'a Server, sender: Sender<Vec<u8>>, transport: Transport, peer: Peer) -> Self {
+        let (revoke, revoked) = channel::bounded(1);
+
+        Connection{
+            id: server.registry.next_id(),
+            user: Mutex::new(auth::Login{
+                email: None,
+                is_authenticated: false,
+                session: None,
+            }),
+            sender,
+            format: Mutex::new(Format::default()),
+            validation: Mutex::new(Validation::Strict),
+            transport,
+            peer,
+            client: Mutex::new(None),
+            revoke,
+            revoked,
+            server,
+        }
+    }
+
+    /// Find the format that requests and responses are currently written in
+    fn format(&self) -> Format {
+        *self.format.lock().unwrap()
+    }
+
+    /// Find how strictly requests on the connection are checked
+    fn validation(&self) -> Validation {
+        *self.validation.lock().unwrap()
+    }
+
+    /// Find the encodings the connection's transport can carry
+    fn encodings(&self) -> &'static [Encoding] {
+        match self.transport {
+            // Lines cannot hold binary encodings, which may contain newlines
+            Transport::Stream(Framing::Lines) => &[Encoding::Json],
+            _ => Encoding::ALL,
+        }
+    }
+
+    /// Find the compression methods the connection's transport can carry
+    fn compressions(&self) -> &'static [compression::Method] {
+        match self.transport {
+            // Compressed frames are binary, and only streams mark them
+            Transport::Stream(Framing::LengthPrefixed) => compression::Method::ALL,
+            _ => &[],
+        }
+    }
+
+    /// Carry out some work unless the user's session is revoked first, or already has been
+    async fn unless_revoked<F: Future>(&self, work: F) -> Option<F::Output> {
+        let work = work.fuse();
+        let revoked = self.revoked.recv().fuse();
+        futures::pin_mut!(work, revoked);
+
+        futures::select! {
+            output = work => Some(output),
+            _ = revoked => None,
+        }
+    }
+
+    /// Replace the user logged in on the connection
+    fn set_user(&self, login: auth::Login) {
+        let mut user = self.user.lock().unwrap();
+        let registry = &self.server.registry;
+        let sessions = &self.server.sessions;
+
+        if let Some(email) = &user.email {
+            registry.unregister(email, self.id);
+        }
+
+        if let Some(session) = user.session {
+            sessions.detach(session, self.id);
+        }
+
+        // Deliver events for the new user to this connection
+        if let (Some(email), true) = (&login.email, login.is_authenticated) {
+            registry.register(email, self.id, self.sender.clone(), self.revoke.clone(), self.format());
+        }
+
+        // Close the connection if the user's session is revoked
+        if let (Some(session), true) = (login.session, login.is_authenticated) {
+            sessions.attach(session, self.id, self.revoke.clone());
+        }
+
+        *user = login;
+    }
+}
+
+impl Drop for Connection<'_> {
+    fn drop(&mut self) {
+        let user = self.user.lock().unwrap();
+
+        if let Some(email) = &user.email {
+            self.server.registry.unregister(email, self.id);
+        }
+
+        if let Some(session) = user.session {
+            self.server.sessions.detach(session, self.id);
+        }
+    }
+}
+
+/// Where a request came from
+struct Origin {
+    peer: Peer,
+    /// The name the client gave, if any
+    client: Option<String>,
+    /// The connection that events can be pushed to, if any
+    connection: Option<usize>,
+}
+
+/// Handle incoming connections from clients, secured with TLS if an acceptor is given
+pub async fn handle_connection<S>(stream: S, peer: Peer, acceptor: Option<&TlsAcceptor>, server: &Server, transport: Transport) -> Result<(), Box<dyn Error>>
+where
+    S: Read + Write + Send + Unpin + 'static,
+{
+    let _open = server.shutdown.track_connection();
+
+    // Report why each connection ended
+    match serve_connection(stream, peer, acceptor, server, transport, None).await {
+        Ok(()) => {
+            info!("Disconnected {}", peer);
+            Ok(())
+        },
+        Err(e) => Err(format!("Disconnected {}: {}", peer, e).into()),
+    }
+}
+
+/// Turn a client away with an error once its connection is secured, without reading its requests
+pub(crate) async fn refuse_connection<S>(stream: S, peer: Peer, acceptor: Option<&TlsAcceptor>, server: &Server, transport: Transport, error: ApiError) -> Result<(), Box<dyn Error>>
+where
+    S: Read + Write + Send + Unpin + 'static,
+{
+    serve_connection(stream, peer, acceptor, server, transport, Some(error)).await
+        .map_err(|e| format!("Could not refuse {}: {}", peer, e).into())
+}
+
+/// Secure a connection if needed and exchange requests and responses until it closes, or answer with an error if refused
+async fn serve_connection<S>(stream: S, peer: Peer, acceptor: Option<&TlsAcceptor>, server: &Server, transport: Transport, refusal: Option<ApiError>) -> Result<(), Box<dyn Error>>
+where
+    S: Read + Write + Send + Unpin + 'static,
+{
+    let acceptor = match acceptor {
+        Some(a) => a,
+        // Local connections never leave the machine
+        None => return serve_stream(stream, peer, server, transport, refusal).await,
     };
 
+    let handshake_timeout = Duration::from_secs(settings::get_or("HANDSHAKE_TIMEOUT", 10)?);
+
     // Perform TLS handshake
     let handshake = acceptor.accept(stream);
-    let mut stream = handshake.await?;
+    let stream = io::timeout(handshake_timeout, handshake).await
+        .map_err(|e| match e.kind() {
+            ioErrKind::TimedOut => ioErr::new(ioErrKind::TimedOut, "TLS handshake took too long"),
+            _ => e,
+        })?;
     info!("Handshake successful");
 
-    // Polling connection
-    loop {
-        match stream.read(&mut buffer).await {
-            Ok(0) => break,
-            Ok(n) => {
-                let result = handle_request(&buffer[..n], &mut user, db_pool).await;
-                
-                if let Err(e) = &result {
-                    error!("{}", e);
-                }
+    serve_stream(stream, peer, server, transport, refusal).await
+}
 
-                let response = format_response(result.ok());
-                task::block_on(stream.write_all(response.as_bytes()))?;
-                stream.flush();
-            },
-            Err(_) => task::sleep(interval).await,
-        }
+/// Exchange requests and responses with a client using a transport until the connection closes
+async fn serve_stream<S>(stream: S, peer: Peer, server: &Server, transport: Transport, refusal: Option<ApiError>) -> Result<(), Box<dyn Error>>
+where
+    S: Read + Write + Send + Unpin + 'static,
+{
+    if let Some(error) = refusal {
+        return refuse_stream(stream, transport, error).await;
     }
 
-    info!("Disconnected {}", address);
+    match transport {
+        Transport::Stream(framing) => handle_stream(stream, framing, peer, server).await?,
+        Transport::WebSocket => websocket::handle_stream(stream, peer, server).await?,
+        Transport::Http => http::handle_stream(stream, peer, server).await?,
+    };
+
     Ok(())
 }
 
-/// Handle a request from a client
-async fn handle_request(data: &[u8], user: &mut auth::Login, db_pool: &PgPool) -> Result<Response, Box<dyn Error>> {
-    // Prepare data
-    let data = str::from_utf8(data)?;
-    let request = Request::from_json(data)?;
+/// Send a client an error in the way its transport expects, then close the connection
+async fn refuse_stream<S>(mut stream: S, transport: Transport, error: ApiError) -> Result<(), Box<dyn Error>>
+where
+    S: Read + Write + Send + Unpin + 'static,
+{
+    match transport {
+        Transport::Stream(framing) => {
+            stream.write_all(&frame::encode(framing, &format_error(error, Format::default()))).await?;
+            stream.flush().await?;
+        },
+        Transport::WebSocket => websocket::refuse_stream(stream, error).await?,
+        Transport::Http => http::refuse_stream(stream, error).await?,
+    };
 
-    // Identify type of request
-    let response = match request.operation {
-        Operation::Verify => {
-            match request.target {
-                Target::Users => request.verify_users(user, db_pool).await?,
-                _ => return Err(Box::new(ioErr::new(ioErrKind::InvalidInput, "Invalid operation"))),
-            }
+    Ok(())
+}
+
+/// Exchange framed requests and responses with a client over a stream
+async fn handle_stream<S>(stream: S, framing: Framing, peer: Peer, server: &Server) -> Result<(), Box<dyn Error>>
+where
+    S: Read + Write + Send + Unpin + 'static,
+{
+    let (reader, mut writer) = stream.split();
+    let frames = frame::read_frames(reader, framing, settings::get_or("MAX_FRAME_SIZE", 1048576)?);
+    let (sender, receiver) = channel::bounded::<Vec<u8>>(queue_size()?);
+
+    // Send responses in the order they are completed
+    let writing = task::spawn(async move {
+        while let Ok(response) = receiver.recv().await {
+            writer.write_all(&frame::encode(framing, &response)).await?;
+            writer.flush().await?;
         }
-        Operation::Create => {
-            match request.target {
-                Target::Conversations => request.create_conversations(user, db_pool).await?,
-                Target::Messages => request.create_messages(user, db_pool).await?,
-                Target::Users => request.create_users(db_pool).await?,
+        Ok::<(), ioErr>(())
+    });
+
+    // Stop sending once every request has been answered
+    let connection = Connection::new(server, sender, Transport::Stream(framing), peer);
+    let result = handle_requests(frames, &connection).await.map_err(|e| e.to_string());
+    drop(connection);
+
+    writing.await?;
+    Ok(result?)
+}
+
+/// Find how many responses and events may wait to be sent to a client before it is disconnected
+fn queue_size() -> Result<usize, Box<dyn Error>> {
+    Ok(settings::get_or("MAX_QUEUED_FRAMES", 256usize)?.max(1))
+}
+
+/// Handle requests from a client concurrently, sending each response once it is ready
+async fn handle_requests<S>(frames: S, connection: &Connection<'_>) -> Result<(), Box<dyn Error>>
+where
+    S: Stream<Item = Result<Vec<u8>, ReadError>>,
+{
+    let max_requests: usize = settings::get_or("MAX_PIPELINED_REQUESTS", 16)?;
+    let max_frame_size: usize = settings::get_or("MAX_FRAME_SIZE", 1048576)?;
+    let compression_threshold: usize = settings::get_or("COMPRESSION_THRESHOLD", 1024)?;
+    let idle_timeout: u64 = settings::get_or("IDLE_TIMEOUT", 300)?;
+    let sender = &connection.sender;
+    let frames = frames.fuse();
+    futures::pin_mut!(frames);
+    let mut in_flight = FuturesUnordered::new();
+    let mut closed = None;
+    let mut is_shutting_down = false;
+    let mut is_revoked = false;
+    let mut is_first = true;
+    let shutdown = connection.server.wait_for_shutdown().fuse();
+    let revoked = connection.revoked.recv().fuse();
+    futures::pin_mut!(shutdown, revoked);
+
+    loop {
+        // Close connections that stay silent with nothing left to answer
+        let is_idle = in_flight.is_empty() && idle_timeout > 0;
+        let idle = async {
+            match is_idle {
+                true => task::sleep(Duration::from_secs(idle_timeout)).await,
+                false => future::pending().await,
             }
-        }
-        Operation::Read => {
-            match request.target {
-                Target::Conversations => request.read_conversations(user, db_pool).await?,
-                Target::Messages => request.read_messages(user, db_pool).await?,
-                Target::Users => request.read_users(user, db_pool).await?,
+        }.fuse();
+        futures::pin_mut!(idle);
+
+        // Stop reading requests while too many are in progress
+        let is_full = in_flight.len() >= max_requests;
+        let next_frame = async {
+            match is_full {
+                true => future::pending().await,
+                false => frames.next().await,
             }
+        }.fuse();
+        futures::pin_mut!(next_frame);
+
+        // Wait for a request while finishing earlier ones
+        let frame = futures::select! {
+            frame = next_frame => frame,
+            response = in_flight.select_next_some() => {
+                sender.send(response).await?;
+                continue;
+            },
+            _ = idle => {
+                let message = "Connection was idle for too long";
+                sender.send(format_error(ApiError::TimedOut(String::from(message)), connection.format())).await?;
+                closed = Some(ioErr::new(ioErrKind::TimedOut, message));
+                break;
+            },
+            // Stop reading requests once the server starts shutting down
+            _ = shutdown => {
+                is_shutting_down = true;
+                break;
+            },
+            // Disconnect straight away once the user's session is revoked elsewhere
+            _ = revoked => {
+                is_revoked = true;
+                closed = Some(revocation_error(sender));
+                break;
+            },
+        };
+
+        let data = match frame {
+            Some(Ok(data)) => data,
+            Some(Err(ReadError::Invalid(message))) => {
+                sender.send(format_error(ApiError::InvalidRequest(message), connection.format())).await?;
+                continue;
+            },
+            // Stop reading once the stream fails
+            Some(Err(ReadError::Closed(e))) => {
+                closed = Some(e);
+                break;
+            },
+            // Stop reading once the client has finished sending requests
+            None => break,
+        };
+
+        let format = connection.format();
+        let requests = match parse_frame(&data, format, connection.validation()) {
+            Ok(r) => r,
+            Err(e) => {
+                error!("{}", e);
+                sender.send(format_response(Err(e), find_request_id(&data, format), format)).await?;
+                continue;
+            },
+        };
+
+        // Clients may introduce themselves first, and are turned away if they are too old
+        if let Requests::Single(Request{id, command: Command::Hello(hello)}) = requests {
+            let mut response = greet(&hello, is_first, connection, max_frame_size, max_requests, compression_threshold);
+            let is_rejected = response.error == Some(ApiError::UnsupportedVersion);
+
+            // The reply is written in the format the HELLO was sent in
+            response.id = id;
+            sender.send(format.write(&response)).await?;
+
+            if is_rejected {
+                break;
+            }
+            is_first = false;
+            continue;
         }
-        Operation::Update => {
-            match request.target {
-                _ => return Err(Box::new(ioErr::new(ioErrKind::InvalidInput, "Invalid operation"))),
+        is_first = false;
+
+        // Requests that change the connection's state wait for earlier requests and run on their own
+        if requests.is_stateful() {
+            let finished = connection.unless_revoked(async {
+                while let Some(response) = in_flight.next().await {
+                    sender.send(response).await?;
+                }
+                sender.send(respond(requests, connection).await).await
+            }).await;
+
+            if finished.transpose()?.is_none() {
+                is_revoked = true;
+                closed = Some(revocation_error(sender));
+                break;
             }
+        } else {
+            in_flight.push(respond(requests, connection));
         }
-        Operation::Delete => {
-            match request.target {
-                _ => return Err(Box::new(ioErr::new(ioErrKind::InvalidInput, "Invalid operation"))),
+    }
+
+    // Finish any requests still in progress, unless the user may no longer make them
+    if !is_revoked {
+        let finished = connection.unless_revoked(async {
+            while let Some(response) = in_flight.next().await {
+                sender.send(response).await?;
             }
+            Ok::<_, SendError<Vec<u8>>>(())
+        }).await;
+
+        if finished.transpose()?.is_none() {
+            is_revoked = true;
+            closed = Some(revocation_error(sender));
         }
+    }
+
+    // Let the client know not to send anything else, unless it can no longer be sent anything
+    let farewell = match (is_revoked, is_shutting_down) {
+        _ if sender.is_closed() => None,
+        (true, _) => Some("REVOKED"),
+        (false, true) => Some("SHUTDOWN"),
+        (false, false) => None,
     };
 
-    Ok(response)
+    if let Some(name) = farewell {
+        let event = Event{
+            name: String::from(name),
+            users: None,
+            messages: None,
+            conversations: None,
+        };
+        sender.send(connection.format().write(&event)).await?;
+    }
+
+    match closed {
+        Some(e) => Err(Box::new(e)),
+        None => Ok(()),
+    }
 }
 
-/// Format an response as JSON or use a default if none is provided
-fn format_response(response: Option<Response>) -> String {
-    let response = match response {
-        Some(r) => r,
-        // If no input provided, use a default failure response
-        None => Response{
-            status: 0,
-            conversations: None,
-            messages: None,
-            users: None,
+/// Explain why a connection was closed straight away, which happens to clients that fall behind on events as well as revoked ones
+fn revocation_error(sender: &Sender<Vec<u8>>) -> ioErr {
+    match sender.is_closed() {
+        true => ioErr::new(ioErrKind::TimedOut, "Client fell behind on events"),
+        false => ioErr::new(ioErrKind::PermissionDenied, "Session was revoked"),
+    }
+}
+
+/// Reply to a client introducing itself with what the server supports, switching to the format it asked for
+fn greet(hello: &Hello, is_first: bool, connection: &Connection<'_>, max_frame_size: usize, max_requests: usize, compression_threshold: usize) -> Response {
+    if !is_first {
+        return Response::from_error(ApiError::InvalidRequest(String::from("HELLO must be sent on its own as the first request on a connection")));
+    }
+
+    let format = negotiate(hello.encoding.as_deref(), connection.encodings(), "encoding")
+        .and_then(|encoding| Ok(Format{
+            encoding: encoding.unwrap_or(Encoding::Json),
+            compression: negotiate(hello.compression.as_deref(), connection.compressions(), "compression")?
+                .map(|method| Compression{
+                    method,
+                    threshold: compression_threshold,
+                    max_size: max_frame_size,
+                }),
+        }));
+
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|t| t.as_secs())
+        .unwrap_or_default();
+
+    let mut response = match (hello.version < protocol::MIN_VERSION, format) {
+        (true, _) => Response::from_error(ApiError::UnsupportedVersion),
+        (false, Err(e)) => Response::from_error(e),
+        (false, Ok(format)) => {
+            *connection.format.lock().unwrap() = format;
+            *connection.client.lock().unwrap() = hello.client.clone();
+            *connection.validation.lock().unwrap() = match hello.lenient {
+                Some(true) => Validation::Lenient,
+                _ => Validation::Strict,
+            };
+            Response::success()
+        },
+    };
+
+    // Rejected clients still learn which version to upgrade to
+    let format = connection.format();
+    response.server = Some(ServerInfo{
+        version: protocol::VERSION,
+        functions: protocol::FUNCTIONS.iter().map(|f| f.to_string()).collect(),
+        capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+        max_frame_size,
+        max_pipelined_requests: max_requests,
+        encodings: connection.encodings().iter().map(|e| e.name().to_string()).collect(),
+        encoding: format.encoding.name().to_string(),
+        compressions: connection.compressions().iter().map(|m| m.name().to_string()).collect(),
+        compression: format.compression.map(|c| c.method.name().to_string()),
+        compression_threshold,
+        time,
+    });
+
+    response
+}
+
+/// Choose an option a client asked for, as long as the connection supports it
+fn negotiate<T>(requested: Option<&str>, supported: &[T], field: &str) -> Result<Option<T>, ApiError>
+where
+    T: FromStr<Err = ApiError> + PartialEq,
+{
+    let choice = match requested {
+        Some(name) => name.parse::<T>()?,
+        None => return Ok(None),
+    };
+
+    match supported.contains(&choice) {
+        true => Ok(Some(choice)),
+        false => Err(ApiError::InvalidField{
+            field: field.to_owned(),
+            reason: String::from("is not supported on this connection"),
+        }),
+    }
+}
+
+/// Requests sent together in a single frame
+enum Requests {
+    /// A request on its own
+    Single(Request),
+    /// Requests to handle in order, paired with their IDs in case they could not be parsed
+    Batch(Vec<(Option<Value>, Result<Request, ApiError>)>),
+}
+
+impl Requests {
+    /// Check whether any of the requests satisfy a condition
+    fn any<F: Fn(&Request) -> bool>(&self, condition: F) -> bool {
+        match self {
+            Requests::Single(request) => condition(request),
+            Requests::Batch(requests) => requests
+                .iter()
+                .any(|(_, r)| matches!(r, Ok(request) if condition(request))),
+        }
+    }
+
+    /// Check whether any of the requests change the connection's state
+    fn is_stateful(&self) -> bool {
+        self.any(|r| matches!(r.command,
+            Command::VerifyUsers(_) | Command::VerifySessions(_) | Command::Logout | Command::RevokeSessions(_) |
+            Command::SubscribeConversations(_) | Command::UnsubscribeConversations(_)))
+    }
+}
+
+/// Handle requests and format the response to send back
+async fn respond(requests: Requests, connection: &Connection<'_>) -> Vec<u8> {
+    let format = connection.format();
+    let changes_user = requests.any(|r| matches!(r.command, Command::VerifyUsers(_) | Command::VerifySessions(_) | Command::Logout));
+    let mut login = connection.user.lock().unwrap().clone();
+
+    let response = match requests {
+        Requests::Single(request) => {
+            let id = request.id.clone();
+            format_response(evaluate(request, &mut login, connection).await, id, format)
         },
+        Requests::Batch(requests) => {
+            // Each request sees the login left by the ones before it
+            let mut responses = Vec::with_capacity(requests.len());
+            for (id, request) in requests {
+                let result = match request {
+                    Ok(r) => evaluate(r, &mut login, connection).await,
+                    Err(e) => {
+                        error!("{}", e);
+                        Err(e)
+                    },
+                };
+
+                let mut response = result.unwrap_or_else(Response::from_error);
+                response.id = id;
+                responses.push(response);
+            }
+
+            format.write(&responses)
+        },
+    };
+
+    if changes_user {
+        connection.set_user(login);
+    }
+
+    response
+}
+
+/// Carry out a request on a connection, logging why it failed if it did
+async fn evaluate(request: Request, login: &mut auth::Login, connection: &Connection<'_>) -> Result<Response, ApiError> {
+    let origin = Origin{
+        peer: connection.peer,
+        client: connection.client.lock().unwrap().clone(),
+        connection: Some(connection.id),
+    };
+
+    let response = dispatch_request(request, login, connection.server, &origin).await;
+    if let Err(e) = &response {
+        error!("{}", e);
+    }
+
+    response
+}
+
+/// Parse a request, or a batch of requests in an array, from a client
+fn parse_frame(data: &[u8], format: Format, validation: Validation) -> Result<Requests, ApiError> {
+    let data = format.read(data)?;
+
+    let requests = match data {
+        Value::Array(items) => Requests::Batch(items
+            .iter()
+            .map(|item| (find_id(item), Request::from_value(item, validation)))
+            .collect()),
+        data => Requests::Single(Request::from_value(&data, validation)?),
     };
 
-    response.to_json()
-}
\ No newline at end of file
+    Ok(requests)
+}
+
+/// Parse a request from a client, which cannot ask for lenient validation without a connection
+fn parse_request(data: &[u8]) -> Result<Request, ApiError> {
+    let data = str::from_utf8(data)?;
+    let request = Request::from_json(data, Validation::Strict)?;
+
+    Ok(request)
+}
+
+/// Find the ID of a request that could not be parsed, so that its response can still be matched to it
+fn find_request_id(data: &[u8], format: Format) -> Option<Value> {
+    find_id(&format.read(data).ok()?)
+}
+
+/// Find the ID of a request in JSON, if it has one
+fn find_id(data: &Value) -> Option<Value> {
+    data.get("id")
+        .filter(|id| !id.is_null())
+        .cloned()
+}
+
+/// Handle a request from a client
+async fn handle_request(data: &[u8], user: &mut auth::Login, server: &Server, origin: &Origin) -> Result<Response, ApiError> {
+    let request = parse_request(data)?;
+    dispatch_request(request, user, server, origin).await
+}
+
+/// Carry out a request on behalf of a user
+async fn dispatch_request(request: Request, user: &mut auth::Login, server: &Server, origin: &Origin) -> Result<Response, ApiError> {
+    check_rate_limit(&request.command, user, origin.peer, server)?;
+
+    match request.command {
+        // Answer without waiting for the database
+        Command::Ping => Ok(Response::success()),
+        Command::Transaction(transaction) => dispatch_transaction(transaction.requests, user, server, origin).await,
+        Command::Hello(_) => Err(ApiError::InvalidRequest(String::from("HELLO must be sent on its own as the first request on a connection"))),
+        command => {
+            let mut db = server.acquire().await?;
+            let mut outbox = Outbox::default();
+            let response = execute_command(command, user, server, origin, &mut db, &mut outbox).await?;
+
+            // Tell other users about changes once they have been made
+            server.registry.publish_all(outbox);
+            Ok(response)
+        }
+    }
+}
+
+/// Carry out several requests on behalf of a user, keeping their changes only if every one succeeds
+async fn dispatch_transaction(requests: Vec<Request>, user: &mut auth::Login, server: &Server, origin: &Origin) -> Result<Response, ApiError> {
+    // Only changes to stored data can be undone
+    let is_undoable = |c: &Command| matches!(c,
+        Command::CreateUsers(_) | Command::CreateConversations(_) | Command::CreateMessages(_) |
+        Command::ReadConversations | Command::ReadMessages(_) | Command::ReadUsers(_));
+
+    if requests.iter().any(|r| !is_undoable(&r.command)) {
+        return Err(ApiError::InvalidRequest(String::from("Transactions may only create or read data")));
+    }
+
+    // Requests count against their own limits, not only the transaction's
+    for request in &requests {
+        check_rate_limit(&request.command, user, origin.peer, server)?;
+    }
+
+    let mut db = server.acquire().await?;
+    let mut tx = sqlx::Connection::begin(&mut *db).await?;
+    let mut outbox = Outbox::default();
+    let mut responses = Vec::with_capacity(requests.len());
+
+    // Changes are kept together, so one item failing fails the whole transaction
+    for mut request in requests {
+        request.command.make_atomic();
+        let mut response = execute_command(request.command, user, server, origin, &mut tx, &mut outbox).await?;
+        response.id = request.id;
+        responses.push(response);
+    }
+
+    tx.commit().await?;
+    server.registry.publish_all(outbox);
+
+    Ok(Response{
+        responses: Some(responses),
+        ..Response::success()
+    })
+}
+
+/// Count a request against the limits on its function, per user once logged in or per address before
+fn check_rate_limit(command: &Command, user: &auth::Login, peer: Peer, server: &Server) -> Result<(), ApiError> {
+    // Addresses are written the same way as for connection limits, so IPv4 clients share one bucket
+    let client = match (&user.email, user.is_authenticated, peer.ip()) {
+        (Some(email), true, _) => format!("user:{}", email),
+        (_, _, Some(ip)) => format!("ip:{}", ip),
+        (_, _, None) => String::from("local"),
+    };
+
+    server.limiter
+        .check(command.function(), &client)
+        .map_err(ApiError::RateLimited)
+}
+
+/// Carry out a command using a database connection, queueing events to publish once its changes are kept
+async fn execute_command(command: Command, user: &mut auth::Login, server: &Server, origin: &Origin, db: &mut PgConnection, outbox: &mut Outbox) -> Result<Response, ApiError> {
+    let registry = &server.registry;
+    let sessions = &server.sessions;
+
+    // Subscriptions only make sense where events can be pushed
+    let subscriber = || origin.connection
+        .ok_or_else(|| ApiError::InvalidRequest(String::from("Subscriptions require a persistent connection")));
+
+    // Identify type of request
+    let response = match command {
+        Command::VerifyUsers(c) => request::verify_users(c, user, sessions, origin.client.as_deref(), origin.peer.ip(), origin.connection, db).await?,
+        Command::VerifySessions(c) => request::verify_sessions(c, user, sessions, origin.connection, db).await?,
+        Command::Logout => request::logout(user, sessions, origin.connection, db).await?,
+        Command::CreateUsers(c) => request::create_users(c, db).await?,
+        Command::CreateConversations(c) => request::create_conversations(c, user, db, outbox).await?,
+        Command::CreateMessages(c) => request::create_messages(c, user, db, outbox).await?,
+        Command::ReadConversations => request::read_conversations(user, db).await?,
+        Command::ReadMessages(c) => request::read_messages(c, user, db).await?,
+        Command::ReadUsers(c) => request::read_users(c, user, db).await?,
+        Command::ReadSessions => request::read_sessions(user, db).await?,
+        Command::RevokeSessions(c) => request::revoke_sessions(c, user, sessions, db).await?,
+        Command::SubscribeConversations(c) => request::subscribe_conversations(c, user, db, registry, subscriber()?).await?,
+        Command::UnsubscribeConversations(c) => request::unsubscribe_conversations(c, user, registry, subscriber()?)?,
+        Command::Hello(_) | Command::Ping | Command::Transaction(_) => {
+            return Err(ApiError::InvalidRequest(String::from("Invalid operation")));
+        }
+    };
+
+    Ok(response)
+}
+
+/// Format the response to a request, or explain why it failed
+fn format_response(response: Result<Response, ApiError>, id: Option<Value>, format: Format) -> Vec<u8> {
+    let mut response = response.unwrap_or_else(Response::from_error);
+
+    response.id = id;
+    format.write(&response)
+}
+
+/// Format a failure response explaining what went wrong
+fn format_error(error: ApiError, format: Format) -> Vec<u8> {
+    format.write(&Response::from_error(error))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Connection, Server, Transport, auth, handle_requests};
+    use crate::frame::Framing;
+    use crate::limiter::{ConnectionLimiter, RateLimiter};
+    use crate::listener::Peer;
+    use crate::registry::Registry;
+    use crate::shutdown::Shutdown;
+    use std::time::Duration;
+    use async_std::channel;
+    use async_std::future::timeout;
+    use async_std::net::TcpListener;
+    use async_std::task;
+    use futures::{future, stream, StreamExt};
+    use sqlx::postgres::PgPoolOptions;
+
+    #[test]
+    fn test_revoked_mid_request() {
+        task::block_on(async {
+            // A database that accepts connections but never answers keeps every request waiting
+            let database = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("postgres://postgres@{}/echo", database.local_addr().unwrap());
+            let server = Server{
+                db_pool: PgPoolOptions::new().connect_timeout(Duration::from_secs(60)).connect_lazy(&url).unwrap(),
+                sessions: auth::Sessions::new(Duration::from_secs(60)),
+                registry: Registry::default(),
+                limiter: RateLimiter::default(),
+                connections: ConnectionLimiter::default(),
+                busy_timeout: Duration::from_secs(60),
+                shutdown: Shutdown::default(),
+            };
+
+            // Both a full pipeline and a request running on its own are cut short
+            for function in ["READ CONVERSATIONS", "SUBSCRIBE CONVERSATIONS"] {
+                let (sender, events) = channel::unbounded();
+                let connection = Connection::new(&server, sender, Transport::Stream(Framing::Lines), Peer::Local);
+                connection.set_user(auth::Login{
+                    email: Some(String::from("1@example.com")),
+                    is_authenticated: true,
+                    session: Some(1),
+                });
+
+                let request = format!("{{\"function\": \"{}\"}}", function);
+                let frames = stream::repeat_with(|| Ok(request.clone().into_bytes()))
+                    .take(32)
+                    .chain(stream::pending());
+
+                let revoking = async {
+                    task::sleep(Duration::from_millis(100)).await;
+                    server.sessions.revoke(1);
+                };
+
+                let (result, _) = future::join(timeout(Duration::from_secs(5), Box::pin(handle_requests(frames, &connection))), revoking).await;
+                let error = result.expect("Revoked connection stayed open").unwrap_err();
+                assert_eq!(error.to_string(), "Session was revoked");
+
+                let farewell = String::from_utf8(events.try_recv().unwrap()).unwrap();
+                assert!(farewell.contains("\"REVOKED\""), "{}", farewell);
+            }
+        });
+    }
+}
@@ -0,0 +1,319 @@
+use crate::listener;
+use crate::protocol;
+
+use std::collections::HashMap;
+use std::io::Error as ioErr;
+use std::io::ErrorKind as ioErrKind;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The limits applied when none are configured
+pub const DEFAULT_LIMITS: &str = "VERIFY USERS=10/60,CREATE USERS=10/60,CREATE MESSAGES=60/10";
+
+/// The number of buckets kept before full ones are thrown away
+const PRUNE_THRESHOLD: usize = 10000;
+
+/// How many requests of a kind a client may make in a burst, and how long the full amount takes to refill
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limit {
+    pub capacity: u32,
+    pub period: Duration,
+}
+
+impl Limit {
+    /// Find the number of requests a client earns back per second
+    fn rate(&self) -> f64 {
+        self.capacity as f64 / self.period.as_secs_f64()
+    }
+}
+
+/// Limits on each function, keyed by its name
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Limits(HashMap<String, Limit>);
+
+impl FromStr for Limits {
+    type Err = ioErr;
+
+    /// Read comma-separated limits written like `CREATE MESSAGES=60/10` for 60 requests every 10 seconds
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |entry: &str| ioErr::new(ioErrKind::InvalidInput, format!("Invalid rate limit '{}'", entry));
+
+        s.split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let (function, limit) = entry.split_once('=').ok_or_else(|| invalid(entry))?;
+                let (capacity, seconds) = limit.split_once('/').ok_or_else(|| invalid(entry))?;
+
+                // Functions are named in the same way as in requests
+                let function = function
+                    .split_whitespace()
+                    .collect::<Vec<&str>>()
+                    .join(" ")
+                    .to_uppercase();
+                if function != "*" && !protocol::FUNCTIONS.contains(&function.as_str()) {
+                    return Err(invalid(entry));
+                }
+
+                let limit = Limit{
+                    capacity: capacity.trim().parse().ok().filter(|c| *c > 0).ok_or_else(|| invalid(entry))?,
+                    period: Duration::from_secs(seconds.trim().parse().ok().filter(|s| *s > 0).ok_or_else(|| invalid(entry))?),
+                };
+
+                Ok((function, limit))
+            })
+            .collect::<Result<_, _>>()
+            .map(Limits)
+    }
+}
+
+impl Limits {
+    /// Find the limit on a function, if it has one
+    fn get(&self, function: &str) -> Option<&Limit> {
+        self.0.get(function).or_else(|| self.0.get("*"))
+    }
+}
+
+/// Requests a client may still make of one kind, refilled as time passes
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// Find how many requests the bucket holds by some time, under the limit it is filled by
+    fn tokens_at(&self, limit: &Limit, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        (self.tokens + elapsed * limit.rate()).min(limit.capacity as f64)
+    }
+}
+
+/// Buckets for each client and function, thrown away once full again
+#[derive(Default)]
+struct Buckets {
+    buckets: HashMap<(String, String), Bucket>,
+    prune_at: usize,
+}
+
+/// Token-bucket limits on how often each client may make each kind of request
+#[derive(Clone, Default)]
+pub struct RateLimiter {
+    limits: Arc<Limits>,
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+impl RateLimiter {
+    /// Create a rate limiter applying limits to each client separately
+    pub fn new(limits: Limits) -> Self {
+        RateLimiter{
+            limits: Arc::new(limits),
+            buckets: Arc::default(),
+        }
+    }
+
+    /// Count a request from a client, or find how long it must wait before making one
+    pub fn check(&self, function: &str, client: &str) -> Result<(), Duration> {
+        self.check_at(function, client, Instant::now())
+    }
+
+    /// Count a request from a client at a given time
+    fn check_at(&self, function: &str, client: &str, now: Instant) -> Result<(), Duration> {
+        let limit = match self.limits.get(function) {
+            Some(l) => *l,
+            None => return Ok(()),
+        };
+
+        let mut state = self.buckets.lock().unwrap();
+
+        // Clients that have not made requests in a while are back to a full bucket anyway
+        if state.buckets.len() >= state.prune_at.max(PRUNE_THRESHOLD) {
+            let limits = &self.limits;
+            state.buckets.retain(|(function, _), bucket| match limits.get(function) {
+                Some(limit) => bucket.tokens_at(limit, now) < limit.capacity as f64,
+                None => false,
+            });
+            state.prune_at = state.buckets.len() * 2;
+        }
+
+        let bucket = state.buckets
+            .entry((function.to_owned(), client.to_owned()))
+            .or_insert(Bucket{
+                tokens: limit.capacity as f64,
+                updated: now,
+            });
+
+        bucket.tokens = bucket.tokens_at(&limit, now);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / limit.rate()))
+        }
+    }
+}
+
+/// Connections open at once, in total and from each address
+#[derive(Default)]
+struct Open {
+    total: usize,
+    by_address: HashMap<IpAddr, usize>,
+}
+
+/// Caps on how many connections may be open at once, in total and from each address, where 0 means no cap
+#[derive(Clone, Default)]
+pub struct ConnectionLimiter {
+    max_total: usize,
+    max_per_address: usize,
+    open: Arc<Mutex<Open>>,
+}
+
+impl ConnectionLimiter {
+    /// Create a limiter allowing some number of connections in total and from each address
+    pub fn new(max_total: usize, max_per_address: usize) -> Self {
+        ConnectionLimiter{
+            max_total,
+            max_per_address,
+            open: Arc::default(),
+        }
+    }
+
+    /// Count a new connection until its slot is dropped, unless the server already has too many
+    pub fn open(&self) -> Option<Slot> {
+        let mut open = self.open.lock().unwrap();
+        if self.max_total > 0 && open.total >= self.max_total {
+            return None;
+        }
+
+        open.total += 1;
+        Some(Slot{
+            open: self.open.clone(),
+            address: None,
+        })
+    }
+
+    /// Count a connection from an address until its slot is dropped, unless that address already has too many
+    pub fn open_from(&self, ip: IpAddr) -> Option<Slot> {
+        let ip = listener::canonical_ip(ip);
+        let mut open = self.open.lock().unwrap();
+        let count = open.by_address.entry(ip).or_insert(0);
+        if self.max_per_address > 0 && *count >= self.max_per_address {
+            return None;
+        }
+
+        *count += 1;
+        Some(Slot{
+            open: self.open.clone(),
+            address: Some(ip),
+        })
+    }
+}
+
+/// A connection counted against a limit, until it is dropped
+pub struct Slot {
+    open: Arc<Mutex<Open>>,
+    address: Option<IpAddr>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut open = self.open.lock().unwrap();
+
+        match self.address {
+            Some(ip) => {
+                let count = open.by_address.entry(ip).or_insert(1);
+                *count -= 1;
+                if *count == 0 {
+                    open.by_address.remove(&ip);
+                }
+            },
+            None => open.total -= 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::limiter::{DEFAULT_LIMITS, PRUNE_THRESHOLD, ConnectionLimiter, Limit, Limits, RateLimiter};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_parse_limits() {
+        let limits: Limits = "verify  users=5/60, *=100/1".parse().unwrap();
+        assert_eq!(limits.get("VERIFY USERS"), Some(&Limit{
+            capacity: 5,
+            period: Duration::from_secs(60),
+        }));
+        assert_eq!(limits.get("PING").unwrap().capacity, 100);
+
+        assert!(DEFAULT_LIMITS.parse::<Limits>().unwrap().get("PING").is_none());
+        assert!("".parse::<Limits>().unwrap().get("PING").is_none());
+        assert!("DELETE USERS=5/60".parse::<Limits>().is_err());
+        assert!("VERIFY USERS=5".parse::<Limits>().is_err());
+        assert!("VERIFY USERS=0/60".parse::<Limits>().is_err());
+        assert!("VERIFY USERS=5/0".parse::<Limits>().is_err());
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new("VERIFY USERS=2/10".parse().unwrap());
+        let start = Instant::now();
+
+        assert!(limiter.check_at("VERIFY USERS", "ip:192.0.2.1", start).is_ok());
+        assert!(limiter.check_at("VERIFY USERS", "ip:192.0.2.1", start).is_ok());
+        assert_eq!(limiter.check_at("VERIFY USERS", "ip:192.0.2.1", start), Err(Duration::from_secs(5)));
+
+        // Clients and functions are limited separately
+        assert!(limiter.check_at("VERIFY USERS", "ip:192.0.2.2", start).is_ok());
+        assert!(limiter.check_at("READ MESSAGES", "ip:192.0.2.1", start).is_ok());
+
+        // Requests are earned back over time
+        let later = start + Duration::from_secs(5);
+        assert!(limiter.check_at("VERIFY USERS", "ip:192.0.2.1", later).is_ok());
+        assert!(limiter.check_at("VERIFY USERS", "ip:192.0.2.1", later).is_err());
+    }
+
+    #[test]
+    fn test_rate_limiter_prune() {
+        let limiter = RateLimiter::new("VERIFY USERS=2/60,CREATE MESSAGES=60/10".parse().unwrap());
+        let start = Instant::now();
+
+        assert!(limiter.check_at("VERIFY USERS", "ip:192.0.2.1", start).is_ok());
+        assert!(limiter.check_at("VERIFY USERS", "ip:192.0.2.1", start).is_ok());
+
+        for client in 1..PRUNE_THRESHOLD {
+            assert!(limiter.check_at("CREATE MESSAGES", &format!("user:{}", client), start).is_ok());
+        }
+
+        // Buckets are pruned by their own limit, whichever function's request set it off
+        let later = start + Duration::from_secs(20);
+        assert!(limiter.check_at("CREATE MESSAGES", "user:0", later).is_ok());
+        assert_eq!(limiter.buckets.lock().unwrap().buckets.len(), 2);
+        assert!(limiter.check_at("VERIFY USERS", "ip:192.0.2.1", later).is_err());
+    }
+
+    #[test]
+    fn test_connection_limiter() {
+        let limiter = ConnectionLimiter::new(2, 1);
+
+        let first = limiter.open().unwrap();
+        let _second = limiter.open().unwrap();
+        assert!(limiter.open().is_none());
+        drop(first);
+        assert!(limiter.open().is_some());
+
+        // IPv4 clients count the same however the socket reports them
+        let client = limiter.open_from("192.0.2.1".parse().unwrap()).unwrap();
+        assert!(limiter.open_from("::ffff:192.0.2.1".parse().unwrap()).is_none());
+        assert!(limiter.open_from("192.0.2.2".parse().unwrap()).is_some());
+        drop(client);
+        assert!(limiter.open_from("192.0.2.1".parse().unwrap()).is_some());
+
+        let unlimited = ConnectionLimiter::new(0, 0);
+        let slots: Vec<_> = (0..100).filter_map(|_| unlimited.open()).collect();
+        assert_eq!(slots.len(), 100);
+    }
+}
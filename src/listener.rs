@@ -0,0 +1,425 @@
+use crate::{Server, Transport};
+use crate::api::error::ApiError;
+use crate::frame::Framing;
+use crate::proxy::{self, TrustedProxies};
+use crate::settings;
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::Error as ioErr;
+use std::io::ErrorKind as ioErrKind;
+use std::io::Result as ioResult;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use async_std::io::{Read, Write};
+use async_std::net::TcpListener;
+use async_std::os::unix::net::UnixListener;
+use async_std::task;
+use async_tls::TlsAcceptor;
+use futures::stream::{Stream, StreamExt};
+use log::{error, info};
+use socket2::{Domain, Type};
+
+/// The number of connections waiting to be accepted that a socket holds
+const BACKLOG: i32 = 1024;
+
+/// Where a listener accepts connections
+#[derive(Clone, Debug, PartialEq)]
+pub enum Address {
+    /// A TCP socket address, whose connections are secured with TLS
+    Tcp(SocketAddr),
+    /// The path of a Unix domain socket, whose connections can only come from the same machine and are not encrypted
+    Unix(PathBuf),
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Tcp(address) => write!(f, "tls://{}", address),
+            Address::Unix(path) => write!(f, "unix://{}", path.display()),
+        }
+    }
+}
+
+/// Where a client connected from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Peer {
+    Tcp(SocketAddr),
+    /// A client on the same machine, connected through a Unix domain socket
+    Local,
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Peer::Tcp(address) => write!(f, "{}", address),
+            Peer::Local => write!(f, "local client"),
+        }
+    }
+}
+
+impl Peer {
+    /// Find the address a client connected from, if it came over a network
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Peer::Tcp(address) => Some(canonical_ip(address.ip())),
+            Peer::Local => None,
+        }
+    }
+}
+
+/// Write an address the same way however the socket reported it
+pub fn canonical_ip(ip: IpAddr) -> IpAddr {
+    // Dual-stack sockets report IPv4 clients as IPv6 addresses
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        ip => ip,
+    }
+}
+
+/// A socket that accepts connections using one transport
+#[derive(Clone, Debug, PartialEq)]
+pub struct Listener {
+    pub address: Address,
+    pub transport: Transport,
+    /// Whether an IPv6 socket refuses IPv4 connections, or the system's default if unset
+    pub v6_only: Option<bool>,
+    /// Whether connections start with a PROXY protocol header naming the client behind a load balancer
+    pub proxy: bool,
+}
+
+impl FromStr for Listener {
+    type Err = ioErr;
+
+    /// Read a listener written like `tls://[::]:63100?transport=websocket&v6only=1` or `unix:///run/echo.sock?framing=lines`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: &str| ioErr::new(ioErrKind::InvalidInput, format!("Invalid listener '{}': {}", s, reason));
+
+        let s = s.trim();
+        let (location, options) = s.split_once('?').unwrap_or((s, ""));
+
+        let address = match location.split_once("://") {
+            Some(("tls", address)) => Address::Tcp(address.parse().map_err(|_| invalid("could not parse socket address"))?),
+            Some(("unix", path)) if !path.is_empty() => Address::Unix(PathBuf::from(path)),
+            _ => return Err(invalid("must start with tls:// or unix://")),
+        };
+
+        let mut transport = "stream";
+        let mut framing = None;
+        let mut v6_only = None;
+        let mut proxy = false;
+
+        for option in options.split('&').filter(|o| !o.is_empty()) {
+            match option.split_once('=') {
+                Some(("transport", t)) => transport = t,
+                Some(("framing", f)) => framing = Some(f.parse::<Framing>()?),
+                Some(("v6only", "1")) => v6_only = Some(true),
+                Some(("v6only", "0")) => v6_only = Some(false),
+                Some(("proxy", "1")) => proxy = true,
+                Some(("proxy", "0")) => proxy = false,
+                _ => return Err(invalid(&format!("unknown option '{}'", option))),
+            }
+        }
+
+        let transport = match (transport.to_lowercase().as_ref(), framing) {
+            ("stream", Some(framing)) => Transport::Stream(framing),
+            ("stream", None) => Transport::Stream(settings::get_or("FRAMING", Framing::LengthPrefixed)?),
+            ("websocket", None) => Transport::WebSocket,
+            ("http", None) => Transport::Http,
+            (_, Some(_)) => return Err(invalid("only streams have a framing")),
+            _ => return Err(invalid("unknown transport")),
+        };
+
+        if v6_only.is_some() && !matches!(address, Address::Tcp(SocketAddr::V6(_))) {
+            return Err(invalid("v6only only applies to IPv6 addresses"));
+        }
+
+        if proxy && !matches!(address, Address::Tcp(_)) {
+            return Err(invalid("proxy only applies to TCP addresses"));
+        }
+
+        Ok(Listener{
+            address,
+            transport,
+            v6_only,
+            proxy,
+        })
+    }
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({:?})", self.address, self.transport)
+    }
+}
+
+impl Listener {
+    /// Open the listener's socket, without accepting connections yet
+    pub async fn bind(self) -> ioResult<Bound> {
+        let proxies = match self.proxy {
+            true => {
+                let trusted: TrustedProxies = settings::get_or("TRUSTED_PROXIES", TrustedProxies::default())?;
+                if trusted.is_empty() {
+                    return Err(ioErr::new(ioErrKind::InvalidInput, format!("{} accepts PROXY headers, but TRUSTED_PROXIES is not set", self)));
+                }
+                Some(Arc::new(trusted))
+            },
+            false => None,
+        };
+
+        let socket = match &self.address {
+            Address::Tcp(address) => {
+                let socket = socket2::Socket::new(Domain::for_address(*address), Type::STREAM, None)?;
+
+                // Let IPv4 and IPv6 listeners share a port, or one IPv6 listener accept both
+                if let Some(v6_only) = self.v6_only {
+                    socket.set_only_v6(v6_only)?;
+                }
+
+                socket.set_reuse_address(true)?;
+                socket.bind(&(*address).into())?;
+                socket.listen(BACKLOG)?;
+                Socket::Tcp(std::net::TcpListener::from(socket).into())
+            },
+            Address::Unix(path) => {
+                remove_stale_socket(path)?;
+                Socket::Unix(UnixListener::bind(path).await?)
+            },
+        };
+
+        info!("Listening on {}", self);
+        Ok(Bound{
+            transport: self.transport,
+            proxies,
+            socket,
+        })
+    }
+
+    /// Read the listeners to open from `LISTENERS`, or from the settings for each port if it is not set
+    pub fn from_env() -> ioResult<Vec<Listener>> {
+        if let Ok(listeners) = env::var("LISTENERS") {
+            return listeners
+                .split(',')
+                .filter(|l| !l.trim().is_empty())
+                .map(str::parse)
+                .collect();
+        }
+
+        let ip = env::var("IP_ADDRESS").unwrap_or_else(|_| String::from("[::]"));
+        let address = |setting: &str, default: Option<&str>| -> ioResult<Option<SocketAddr>> {
+            let port = match (env::var(setting), default) {
+                (Ok(port), _) => port,
+                (Err(_), Some(port)) => port.to_owned(),
+                (Err(_), None) => return Ok(None),
+            };
+
+            format!("{}:{}", ip, port)
+                .parse()
+                .map(Some)
+                .map_err(|_| ioErr::new(ioErrKind::InvalidInput, format!("Could not parse {}", setting)))
+        };
+
+        let transports = [
+            ("PORT_NUMBER", Some("63100"), Transport::Stream(settings::get_or("FRAMING", Framing::LengthPrefixed)?)),
+            ("WEBSOCKET_PORT_NUMBER", None, Transport::WebSocket),
+            ("HTTP_PORT_NUMBER", None, Transport::Http),
+        ];
+
+        let mut listeners = Vec::new();
+        for (setting, default, transport) in transports.iter() {
+            if let Some(address) = address(setting, *default)? {
+                listeners.push(Listener{
+                    address: Address::Tcp(address),
+                    transport: *transport,
+                    v6_only: None,
+                    proxy: false,
+                });
+            }
+        }
+
+        Ok(listeners)
+    }
+}
+
+/// A socket opened for a listener
+enum Socket {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// A listener whose socket is open, ready to accept connections
+pub struct Bound {
+    transport: Transport,
+    /// Load balancers trusted to name the clients they connect for, if the listener accepts PROXY headers
+    proxies: Option<Arc<TrustedProxies>>,
+    socket: Socket,
+}
+
+impl Bound {
+    /// Accept connections until the server shuts down, handling each with the listener's transport
+    pub async fn serve(self, acceptor: TlsAcceptor, server: Server) -> ioResult<()> {
+        let transport = self.transport;
+
+        match self.socket {
+            Socket::Tcp(socket) => {
+                let incoming = socket
+                    .incoming()
+                    .map(|stream| stream.and_then(|s| Ok((Peer::Tcp(s.peer_addr()?), s))));
+                accept(incoming, Some(acceptor), self.proxies, transport, server).await
+            },
+            Socket::Unix(socket) => {
+                let path = socket.local_addr()?.as_pathname().map(Path::to_path_buf);
+                let incoming = socket
+                    .incoming()
+                    .map(|stream| stream.map(|s| (Peer::Local, s)));
+                let result = accept(incoming, None, None, transport, server).await;
+
+                // Leave nothing behind once the server stops
+                if let Some(path) = path {
+                    fs::remove_file(path).ok();
+                }
+                result
+            },
+        }
+    }
+}
+
+/// Handle each incoming connection in its own task until the server shuts down
+async fn accept<I, S>(incoming: I, acceptor: Option<TlsAcceptor>, proxies: Option<Arc<TrustedProxies>>, transport: Transport, server: Server) -> ioResult<()>
+where
+    I: Stream<Item = ioResult<(Peer, S)>>,
+    S: Read + Write + Send + Unpin + 'static,
+{
+    let incoming = incoming.take_until(server.wait_for_shutdown());
+    futures::pin_mut!(incoming);
+
+    while let Some(connection) = incoming.next().await {
+        let (peer, mut stream) = connection?;
+        let acceptor = acceptor.clone();
+        let proxies = proxies.clone();
+        let server = server.clone();
+
+        task::spawn(async move {
+            // Count the connection against the total before waiting on anything it sends
+            let slot = server.connections.open();
+
+            // Clients behind a load balancer are named by the header it sends first
+            let peer = match &proxies {
+                Some(trusted) => match proxy::accept(&mut stream, peer, trusted).await {
+                    Ok(peer) => peer,
+                    Err(e) => {
+                        error!("{}", e);
+                        return;
+                    },
+                },
+                None => peer,
+            };
+
+            // Turn clients away once the server has no room for them, or one client would take every connection
+            let address_slot = peer.ip().map(|ip| server.connections.open_from(ip));
+            let refusal = match (&slot, &address_slot) {
+                (None, _) => Some("too many connections"),
+                (_, Some(None)) => Some("too many connections from this address"),
+                _ => None,
+            };
+
+            if let Some(reason) = refusal {
+                error!("Refused connection from {}: {}", peer, reason);
+                let result = crate::refuse_connection(stream, peer, acceptor.as_ref(), &server, transport, ApiError::ServerBusy).await;
+                if let Err(e) = result {
+                    error!("{}", e);
+                }
+                return;
+            }
+            let _slots = (slot, address_slot);
+
+            info!("Successful connection from {}", peer);
+
+            let result = crate::handle_connection(stream, peer, acceptor.as_ref(), &server, transport).await;
+
+            if let Err(e) = result {
+                error!("{}", e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// Remove a socket left behind by a server that did not shut down cleanly, refusing to replace anything else
+fn remove_stale_socket(path: &Path) -> ioResult<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(m) => m,
+        Err(_) => return Ok(()),
+    };
+
+    if !metadata.file_type().is_socket() {
+        return Err(ioErr::new(ioErrKind::AlreadyExists, format!("{} exists and is not a socket", path.display())));
+    }
+
+    // Another server is still accepting connections on the socket
+    if std::os::unix::net::UnixStream::connect(path).is_ok() {
+        return Err(ioErr::new(ioErrKind::AddrInUse, format!("{} is already in use", path.display())));
+    }
+
+    fs::remove_file(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Transport;
+    use crate::frame::Framing;
+    use crate::listener::{Address, Listener, Peer};
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_parse_listener() {
+        let listener: Listener = "tls://[::]:63101?transport=websocket&v6only=1".parse().unwrap();
+        assert_eq!(listener, Listener{
+            address: Address::Tcp("[::]:63101".parse().unwrap()),
+            transport: Transport::WebSocket,
+            v6_only: Some(true),
+            proxy: false,
+        });
+
+        let listener: Listener = " unix:///run/echo.sock?framing=lines".parse().unwrap();
+        assert_eq!(listener, Listener{
+            address: Address::Unix(PathBuf::from("/run/echo.sock")),
+            transport: Transport::Stream(Framing::Lines),
+            v6_only: None,
+            proxy: false,
+        });
+        assert_eq!(listener.to_string(), "unix:///run/echo.sock (Stream(Lines))");
+
+        let listener: Listener = "tls://0.0.0.0:63100?framing=length&proxy=1".parse().unwrap();
+        assert_eq!(listener.transport, Transport::Stream(Framing::LengthPrefixed));
+        assert!(listener.proxy);
+    }
+
+    #[test]
+    fn test_invalid_listener() {
+        assert!("0.0.0.0:63100".parse::<Listener>().is_err());
+        assert!("tcp://0.0.0.0:63100".parse::<Listener>().is_err());
+        assert!("tls://localhost".parse::<Listener>().is_err());
+        assert!("unix://".parse::<Listener>().is_err());
+        assert!("tls://0.0.0.0:63100?v6only=1".parse::<Listener>().is_err());
+        assert!("tls://[::]:63100?transport=http&framing=lines".parse::<Listener>().is_err());
+        assert!("tls://[::]:63100?transport=carrier-pigeon".parse::<Listener>().is_err());
+        assert!("tls://[::]:63100?compression=1".parse::<Listener>().is_err());
+        assert!("unix:///run/echo.sock?proxy=1".parse::<Listener>().is_err());
+    }
+
+    #[test]
+    fn test_peer_ip() {
+        let mapped = Peer::Tcp("[::ffff:192.0.2.1]:4000".parse().unwrap());
+        assert_eq!(mapped.ip(), Some("192.0.2.1".parse().unwrap()));
+
+        let v6 = Peer::Tcp("[2001:db8::1]:4000".parse().unwrap());
+        assert_eq!(v6.ip(), Some("2001:db8::1".parse().unwrap()));
+        assert_eq!(Peer::Local.ip(), None);
+    }
+}
@@ -1,15 +1,21 @@
 use std::env;
-use std::net::SocketAddr;
-use async_std::prelude::*;
-use async_std::net::TcpListener;
-use async_std::task;
+use std::process;
+use std::time::Duration;
 use dotenv;
+use futures::future;
 use log::{error, info};
 
 use echo_server;
+use echo_server::listener::Listener;
 
 #[async_std::main]
 async fn main() -> std::io::Result<()> {
+    // Describe the protocol instead of serving it if asked
+    if env::args().any(|a| a == "--schema") {
+        println!("{:#}", echo_server::protocol::schema());
+        return Ok(());
+    }
+
     dotenv::dotenv().ok();
     env_logger::init();
 
@@ -17,38 +23,49 @@ async fn main() -> std::io::Result<()> {
     let pool = echo_server::database::init_db().await
         .expect("Could not initialize database");
 
-    // Choose a socket address
-    let socket_addr: SocketAddr = format!("{}:{}",
-            env::var("IP_ADDRESS").unwrap_or(String::from("[::]")),
-            env::var("PORT_NUMBER").unwrap_or(String::from("63100")))
-        .parse()
-        .expect("Could not parse socket address");
+    // Choose where to accept connections, and how requests are exchanged on each
+    let listeners = Listener::from_env()
+        .expect("Could not parse listeners");
 
     // Set up TLS
     let acceptor = echo_server::tls::get_acceptor().await
         .expect("Could not accept TLS handshake");
 
-    // Listen for incoming connections
-    let listener = TcpListener::bind(socket_addr).await?;
-    let mut incoming = listener.incoming();
+    let server = echo_server::Server::new(pool)
+        .expect("Could not read server settings");
 
-    info!("Listening on port {}", socket_addr.port());
+    // Finish up when asked to stop, or stop immediately if asked twice
+    let deadline = Duration::from_secs(env::var("SHUTDOWN_TIMEOUT")
+        .unwrap_or(String::from("30"))
+        .parse()
+        .expect("Could not parse SHUTDOWN_TIMEOUT"));
 
-    while let Some(stream) = incoming.next().await {
-        let stream = stream?;
-        let acceptor = acceptor.clone();
-        let pool = pool.clone();
+    let signalled = server.clone();
+    ctrlc::set_handler(move || {
+        if signalled.shutdown() {
+            info!("Shutting down");
+        } else {
+            process::exit(1);
+        }
+    }).expect("Could not handle signals");
 
-        info!("Successful connection from {}", stream.peer_addr()?);
+    // Open every socket before accepting connections on any of them
+    let mut bound = Vec::with_capacity(listeners.len());
+    for listener in listeners {
+        bound.push(listener.bind().await?);
+    }
 
-        task::spawn(async move {
-            let result = echo_server::handle_connection(stream, &acceptor, &pool).await;
+    let listening = bound
+        .into_iter()
+        .map(|b| b.serve(acceptor.clone(), server.clone()));
 
-            if let Err(e) = result {
-                error!("{}", e);
-            }
-        });
+    for result in future::join_all(listening).await {
+        if let Err(e) = result {
+            error!("{}", e);
+        }
     }
 
+    server.close(deadline).await;
+    info!("Shut down");
     Ok(())
 }
@@ -0,0 +1,449 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+/// The version of the protocol the server speaks
+pub const VERSION: u32 = 1;
+
+/// The oldest version of the protocol the server still accepts
+pub const MIN_VERSION: u32 = 1;
+
+/// A request sent by a client, which the server answers with a response carrying the same ID
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub struct Request {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<Value>,
+    #[serde(flatten)]
+    pub command: Command,
+}
+
+/// Define every command alongside the function naming it, so that parsing, naming and listing them cannot disagree
+///
+/// Commands are read with `ReadCommand::fields` unless they take nothing but their function,
+/// or name another method of `ReadCommand` with `via`.
+macro_rules! commands {
+    ($($variant:ident $(($fields:ty))? = $function:literal $(via $method:ident)?,)*) => {
+        /// Something a client asks the server to do, named by the request's `function`
+        #[derive(Debug, Deserialize, Serialize, JsonSchema)]
+        #[serde(tag = "function")]
+        pub enum Command {
+            $(
+                #[serde(rename = $function)]
+                $variant $(($fields))?,
+            )*
+        }
+
+        /// Every function a request can name
+        pub const FUNCTIONS: &[&str] = &[$($function),*];
+
+        impl Command {
+            /// Find the name of the function a command carries out, as written in requests
+            pub fn function(&self) -> &'static str {
+                match self {
+                    $(commands!(@pattern $variant $(($fields))?) => $function,)*
+                }
+            }
+
+            /// Read the command for a function named in its normal form, or None if there is no such function
+            pub fn read<R: ReadCommand>(function: &str, reader: R) -> Option<Result<Self, R::Error>> {
+                let command = match function {
+                    $($function => commands!(@read reader $variant $(($fields))? $(via $method)?),)*
+                    _ => return None,
+                };
+
+                Some(command)
+            }
+        }
+    };
+    (@pattern $variant:ident ($fields:ty)) => { Command::$variant(_) };
+    (@pattern $variant:ident) => { Command::$variant };
+    (@read $reader:ident $variant:ident ($fields:ty) via $method:ident) => { $reader.$method().map(Command::$variant) };
+    (@read $reader:ident $variant:ident ($fields:ty)) => { $reader.fields::<$fields>().map(Command::$variant) };
+    (@read $reader:ident $variant:ident) => { $reader.nothing().map(|_| Command::$variant) };
+}
+
+/// Reads the fields of a request for the command it names, however the server chooses to check them
+pub trait ReadCommand {
+    type Error;
+
+    /// Read the fields of a command
+    fn fields<T: DeserializeOwned>(self) -> Result<T, Self::Error>;
+
+    /// Check that a command that takes nothing but its function was sent nothing else
+    fn nothing(self) -> Result<(), Self::Error>;
+
+    /// Read the requests making up a transaction, which are parsed as requests of their own
+    fn transaction(self) -> Result<Transaction, Self::Error>;
+}
+
+commands! {
+    Hello(Hello) = "HELLO",
+    VerifyUsers(VerifyUsers) = "VERIFY USERS",
+    VerifySessions(VerifySessions) = "VERIFY SESSIONS",
+    Logout = "LOGOUT",
+    CreateUsers(CreateUsers) = "CREATE USERS",
+    CreateConversations(CreateConversations) = "CREATE CONVERSATIONS",
+    CreateMessages(CreateMessages) = "CREATE MESSAGES",
+    ReadConversations = "READ CONVERSATIONS",
+    ReadMessages(ReadMessages) = "READ MESSAGES",
+    ReadUsers(ReadUsers) = "READ USERS",
+    ReadSessions = "READ SESSIONS",
+    RevokeSessions(RevokeSessions) = "REVOKE SESSIONS",
+    SubscribeConversations(Subscription) = "SUBSCRIBE CONVERSATIONS",
+    UnsubscribeConversations(Subscription) = "UNSUBSCRIBE CONVERSATIONS",
+    Ping = "PING",
+    Transaction(Transaction) = "TRANSACTION" via transaction,
+}
+
+impl Command {
+    /// Require every item a command creates to be stored for it to succeed
+    pub fn make_atomic(&mut self) {
+        match self {
+            Command::CreateUsers(c) => c.atomic = true,
+            Command::CreateMessages(c) => c.atomic = true,
+            _ => {},
+        }
+    }
+}
+
+/// Introduce a client at the start of a connection
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Hello {
+    /// The version of the protocol the client speaks
+    pub version: u32,
+    /// The encoding to use for the rest of the connection, or JSON if none is given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
+    /// The method used to compress large frames for the rest of the connection, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<String>,
+    /// The name of the client, shown to the user alongside sessions it logs in with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client: Option<String>,
+    /// Whether later requests may skip unknown fields and malformed objects, as older versions of the server allowed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lenient: Option<bool>,
+}
+
+/// Log in as a user for the rest of the connection
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct VerifyUsers {
+    #[serde(rename = "users", with = "single")]
+    #[schemars(with = "[Credentials; 1]")]
+    pub user: Credentials,
+}
+
+/// Log in again with a session token issued by an earlier VERIFY USERS, without sending a password
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct VerifySessions {
+    #[serde(rename = "sessions", with = "single")]
+    #[schemars(with = "[SessionToken; 1]")]
+    pub session: SessionToken,
+}
+
+/// Register new users
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct CreateUsers {
+    pub users: Vec<NewUser>,
+    /// Store every user or none of them
+    #[serde(default)]
+    pub atomic: bool,
+}
+
+/// Start a conversation with other users
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct CreateConversations {
+    pub users: Vec<Participant>,
+    #[serde(rename = "conversations", with = "single")]
+    #[schemars(with = "[NewConversation; 1]")]
+    pub conversation: NewConversation,
+}
+
+/// Send messages to a conversation
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct CreateMessages {
+    #[serde(rename = "conversations", with = "single")]
+    #[schemars(with = "[ConversationId; 1]")]
+    pub conversation: ConversationId,
+    pub messages: Vec<NewMessage>,
+    /// Store every message or none of them
+    #[serde(default)]
+    pub atomic: bool,
+}
+
+/// Read the messages in a conversation
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ReadMessages {
+    #[serde(rename = "conversations", with = "single")]
+    #[schemars(with = "[ConversationId; 1]")]
+    pub conversation: ConversationId,
+}
+
+/// Read the participants in a conversation
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ReadUsers {
+    #[serde(rename = "conversations", with = "single")]
+    #[schemars(with = "[ConversationId; 1]")]
+    pub conversation: ConversationId,
+}
+
+/// Change which conversations a connection receives events for, or every conversation if none are listed
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Subscription {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conversations: Option<Vec<ConversationId>>,
+}
+
+/// Log out sessions on other devices, or every session except the current one if none are listed
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct RevokeSessions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sessions: Option<Vec<SessionId>>,
+}
+
+/// Carry out requests together, keeping their changes only if every one succeeds
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Transaction {
+    pub requests: Vec<Request>,
+}
+
+/// The email and password a user logs in with
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Credentials {
+    pub email: String,
+    pub password: String,
+}
+
+/// A token identifying a session to resume
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct SessionToken {
+    pub token: String,
+}
+
+/// A user to register
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct NewUser {
+    pub email: String,
+    pub password: String,
+    #[serde(with = "bytes")]
+    #[schemars(with = "String")]
+    pub public_key: Vec<u8>,
+}
+
+/// A user to add to a conversation
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Participant {
+    pub email: String,
+}
+
+/// A conversation to start
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct NewConversation {
+    pub name: String,
+}
+
+/// A reference to an existing conversation
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ConversationId {
+    pub id: i32,
+}
+
+/// A reference to a session a user is logged in with
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct SessionId {
+    pub id: i32,
+}
+
+/// A message to send, encrypted and signed by the client
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct NewMessage {
+    #[serde(with = "bytes")]
+    #[schemars(with = "String")]
+    pub data: Vec<u8>,
+    #[serde(with = "bytes")]
+    #[schemars(with = "String")]
+    pub media_type: Vec<u8>,
+    #[serde(with = "bytes")]
+    #[schemars(with = "String")]
+    pub timestamp: Vec<u8>,
+    #[serde(with = "bytes")]
+    #[schemars(with = "String")]
+    pub signature: Vec<u8>,
+}
+
+/// What a server supports, sent in reply to HELLO
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerInfo {
+    /// The version of the protocol the server speaks, which clients speaking a newer version should fall back to
+    pub version: u32,
+    pub functions: Vec<String>,
+    pub capabilities: Vec<String>,
+    pub max_frame_size: usize,
+    pub max_pipelined_requests: usize,
+    /// Encodings the connection can switch to
+    pub encodings: Vec<String>,
+    /// The encoding used after this reply
+    pub encoding: String,
+    /// Compression methods the connection can switch to
+    pub compressions: Vec<String>,
+    /// The compression method used after this reply, if any
+    pub compression: Option<String>,
+    /// Frames smaller than this many bytes are not compressed
+    pub compression_threshold: usize,
+    /// Seconds since the Unix epoch
+    pub time: u64,
+}
+
+/// A session that clients can resume on later connections, sent in reply to VERIFY USERS and VERIFY SESSIONS
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+pub struct SessionInfo {
+    pub id: i32,
+    /// Only sent in reply to VERIFY USERS, since the server keeps just a digest of it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    /// Seconds since the Unix epoch after which the token is no longer accepted
+    pub expires: u64,
+}
+
+/// Describe every request the server accepts as a JSON schema
+pub fn schema() -> Value {
+    serde_json::to_value(schemars::schema_for!(Request))
+        .expect("Could not format schema")
+}
+
+/// Byte fields, which are sent as base64
+mod bytes {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use serde::de::Error;
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&base64::encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        base64::decode(encoded).map_err(|_| D::Error::custom("must be base64"))
+    }
+}
+
+/// Lists that must hold exactly one item
+mod single {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use serde::de::Error;
+
+    pub fn serialize<T: Serialize, S: Serializer>(item: &T, serializer: S) -> Result<S::Ok, S::Error> {
+        [item].serialize(serializer)
+    }
+
+    pub fn deserialize<'de, T: Deserialize<'de>, D: Deserializer<'de>>(deserializer: D) -> Result<T, D::Error> {
+        let mut items = Vec::<T>::deserialize(deserializer)?;
+        match items.len() {
+            1 => Ok(items.remove(0)),
+            _ => Err(D::Error::custom("must hold exactly one item")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::{Command, ReadCommand, Request, Transaction, FUNCTIONS, schema};
+    use serde::de::DeserializeOwned;
+    use serde_json::json;
+
+    /// Reads commands without fields, failing to read any others
+    struct Empty;
+
+    impl ReadCommand for Empty {
+        type Error = ();
+
+        fn fields<T: DeserializeOwned>(self) -> Result<T, ()> {
+            Err(())
+        }
+
+        fn nothing(self) -> Result<(), ()> {
+            Ok(())
+        }
+
+        fn transaction(self) -> Result<Transaction, ()> {
+            Ok(Transaction{
+                requests: Vec::new(),
+            })
+        }
+    }
+
+    #[test]
+    fn test_request_round_trip() {
+        let json = json!({
+            "id": 7,
+            "function": "CREATE MESSAGES",
+            "conversations": [{"id": 2}],
+            "messages": [{
+                "data": "ZGF0YQ==",
+                "mediaType": "dGV4dC9wbGFpbg==",
+                "timestamp": "dGltZXN0YW1w",
+                "signature": "c2lnbmF0dXJl",
+            }],
+            "atomic": true,
+        });
+
+        let request: Request = serde_json::from_value(json.clone()).unwrap();
+        match &request.command {
+            Command::CreateMessages(c) => {
+                assert_eq!(c.conversation.id, 2);
+                assert_eq!(c.messages[0].data, b"data");
+                assert_eq!(c.messages[0].media_type, b"text/plain");
+            },
+            c => panic!("Unexpected command {:?}", c),
+        }
+
+        assert_eq!(request.command.function(), "CREATE MESSAGES");
+        assert_eq!(serde_json::to_value(&request).unwrap(), json);
+    }
+
+    #[test]
+    fn test_functions() {
+        for function in FUNCTIONS {
+            match Command::read(function, Empty) {
+                Some(Ok(command)) => assert_eq!(command.function(), *function),
+                Some(Err(())) => {},
+                None => panic!("{} cannot be read", function),
+            }
+        }
+
+        assert!(matches!(Command::read("PING", Empty), Some(Ok(Command::Ping))));
+        assert!(matches!(Command::read("TRANSACTION", Empty), Some(Ok(Command::Transaction(_)))));
+        assert!(Command::read("ping", Empty).is_none());
+    }
+
+    #[test]
+    fn test_schema() {
+        let schema = schema().to_string();
+
+        for function in FUNCTIONS {
+            assert!(schema.contains(&format!("\"{}\"", function)), "{} is not in the schema", function);
+        }
+
+        assert!(schema.contains("\"publicKey\""));
+    }
+}
@@ -0,0 +1,303 @@
+use crate::listener::{self, Peer};
+use crate::settings;
+
+use std::convert::TryInto;
+use std::io::Error as ioErr;
+use std::io::ErrorKind as ioErrKind;
+use std::io::Result as ioResult;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::{self, FromStr};
+use std::time::Duration;
+use async_std::io::{self, Read, ReadExt};
+use log::info;
+
+/// The bytes that start a version 2 header
+const SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The longest a version 1 header can be, including its line ending
+const MAX_V1_LENGTH: usize = 107;
+
+/// A range of addresses written in CIDR notation, such as `10.0.0.0/8`
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Network {
+    address: IpAddr,
+    prefix: u32,
+}
+
+impl Network {
+    /// Check whether an address is in the range
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.address, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            },
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            },
+            _ => false,
+        }
+    }
+}
+
+/// Load balancers trusted to say where their connections come from
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TrustedProxies {
+    networks: Vec<Network>,
+}
+
+impl FromStr for TrustedProxies {
+    type Err = ioErr;
+
+    /// Read a comma-separated list of addresses and ranges of addresses
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |entry: &str| ioErr::new(ioErrKind::InvalidInput, format!("Invalid trusted proxy '{}'", entry));
+
+        let networks = s
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let (address, prefix) = entry.split_once('/').unwrap_or((entry, ""));
+                let address: IpAddr = address.parse().map_err(|_| invalid(entry))?;
+                let max_prefix = match address {
+                    IpAddr::V4(_) => 32,
+                    IpAddr::V6(_) => 128,
+                };
+
+                let prefix = match prefix {
+                    "" => max_prefix,
+                    p => p.parse().ok().filter(|p| *p <= max_prefix).ok_or_else(|| invalid(entry))?,
+                };
+
+                Ok(Network{
+                    address,
+                    prefix,
+                })
+            })
+            .collect::<ioResult<_>>()?;
+
+        Ok(TrustedProxies{
+            networks,
+        })
+    }
+}
+
+impl TrustedProxies {
+    /// Check whether no proxies are trusted
+    pub fn is_empty(&self) -> bool {
+        self.networks.is_empty()
+    }
+
+    /// Check whether connections from an address may say where they come from
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = listener::canonical_ip(ip);
+        self.networks.iter().any(|n| n.contains(ip))
+    }
+}
+
+/// Find the client behind a trusted load balancer from the header it sends before anything else
+///
+/// Connections from untrusted addresses are refused, since anyone could claim to be anyone.
+/// Proxies that do not name a client, such as when checking the server's health, are treated as the client.
+pub async fn accept<R>(stream: &mut R, peer: Peer, trusted: &TrustedProxies) -> ioResult<Peer>
+where
+    R: Read + Unpin,
+{
+    let proxy = match peer {
+        Peer::Tcp(address) => address,
+        Peer::Local => return Ok(peer),
+    };
+
+    if !trusted.contains(proxy.ip()) {
+        return Err(ioErr::new(ioErrKind::PermissionDenied, format!("Refused connection from {}, which is not a trusted proxy", proxy)));
+    }
+
+    let timeout = Duration::from_secs(settings::get_or("HANDSHAKE_TIMEOUT", 10)
+        .map_err(|e| ioErr::new(ioErrKind::InvalidInput, e))?);
+
+    let client = io::timeout(timeout, read_header(stream)).await
+        .map_err(|e| ioErr::new(e.kind(), format!("Could not read PROXY header from {}: {}", proxy, e)))?;
+
+    match client {
+        Some(client) => {
+            info!("Connection from {} through proxy {}", client, proxy);
+            Ok(Peer::Tcp(client))
+        },
+        None => Ok(peer),
+    }
+}
+
+/// Read a version 1 or 2 header, finding the client's address if it gives one
+async fn read_header<R>(stream: &mut R) -> ioResult<Option<SocketAddr>>
+where
+    R: Read + Unpin,
+{
+    // Both versions are at least this long, so nothing after the header is read
+    let mut start = [0u8; 12];
+    stream.read_exact(&mut start).await?;
+
+    if start == SIGNATURE {
+        let mut header = [0u8; 4];
+        stream.read_exact(&mut header).await?;
+
+        let mut body = vec![0u8; u16::from_be_bytes([header[2], header[3]]) as usize];
+        stream.read_exact(&mut body).await?;
+
+        return parse_v2(&header, &body);
+    }
+
+    if !start.starts_with(b"PROXY ") {
+        return Err(invalid("Connection did not start with a PROXY header"));
+    }
+
+    // Read one byte at a time to stop exactly at the end of the line
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= MAX_V1_LENGTH {
+            return Err(invalid("PROXY header is too long"));
+        }
+
+        let mut byte = [0u8; 1];
+        stream.read_exact(&mut byte).await?;
+        line.push(byte[0]);
+    }
+
+    let line = str::from_utf8(&line[..line.len() - 2]).map_err(|_| invalid("PROXY header must be ASCII"))?;
+    parse_v1(line)
+}
+
+/// Read a version 1 header, such as `PROXY TCP4 203.0.113.7 192.0.2.1 56324 443`, without its line ending
+fn parse_v1(line: &str) -> ioResult<Option<SocketAddr>> {
+    let parts: Vec<&str> = line.split(' ').collect();
+
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", protocol @ ("TCP4" | "TCP6"), source, _, port, _] => {
+            let ip: IpAddr = source.parse().map_err(|_| invalid("PROXY header has an invalid address"))?;
+            let port: u16 = port.parse().map_err(|_| invalid("PROXY header has an invalid port"))?;
+
+            match (*protocol, ip) {
+                ("TCP4", IpAddr::V4(_)) | ("TCP6", IpAddr::V6(_)) => Ok(Some(SocketAddr::new(ip, port))),
+                _ => Err(invalid("PROXY header address does not match its protocol")),
+            }
+        },
+        _ => Err(invalid("PROXY header is malformed")),
+    }
+}
+
+/// Read the rest of a version 2 header after its signature: the version and command, address family, length and addresses
+fn parse_v2(header: &[u8; 4], body: &[u8]) -> ioResult<Option<SocketAddr>> {
+    if header[0] >> 4 != 2 {
+        return Err(invalid("PROXY header has an unsupported version"));
+    }
+
+    match header[0] & 0x0f {
+        // Connections the proxy makes itself, such as health checks
+        0x0 => return Ok(None),
+        0x1 => {},
+        _ => return Err(invalid("PROXY header has an unknown command")),
+    }
+
+    let too_short = || invalid("PROXY header is too short for its addresses");
+
+    // Only TCP connections can stand in for a stream, so datagram or unspecified protocols are not trusted
+    if matches!(header[1] >> 4, 0x1 | 0x2) && header[1] & 0x0f != 0x1 {
+        return Err(invalid("PROXY header has an unsupported transport protocol"));
+    }
+
+    match header[1] >> 4 {
+        // IPv4: source address, destination address, source port, destination port
+        0x1 => {
+            let addresses = body.get(..12).ok_or_else(too_short)?;
+            let ip: [u8; 4] = addresses[0..4].try_into().map_err(|_| too_short())?;
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(Ipv4Addr::from(ip)), port)))
+        },
+        // IPv6, laid out like IPv4
+        0x2 => {
+            let addresses = body.get(..36).ok_or_else(too_short)?;
+            let ip: [u8; 16] = addresses[0..16].try_into().map_err(|_| too_short())?;
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(ip)), port)))
+        },
+        // Unspecified or Unix socket addresses say nothing useful about the client
+        _ => Ok(None),
+    }
+}
+
+/// Describe a malformed header
+fn invalid(message: &str) -> ioErr {
+    ioErr::new(ioErrKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::proxy::{SIGNATURE, TrustedProxies, parse_v1, read_header};
+    use std::net::SocketAddr;
+    use async_std::task;
+
+    #[test]
+    fn test_trusted_proxies() {
+        let trusted: TrustedProxies = "10.0.0.0/8, 192.168.1.5,fd00::/8".parse().unwrap();
+
+        assert!(trusted.contains("10.20.30.40".parse().unwrap()));
+        assert!(trusted.contains("::ffff:10.0.0.1".parse().unwrap()));
+        assert!(trusted.contains("192.168.1.5".parse().unwrap()));
+        assert!(!trusted.contains("192.168.1.6".parse().unwrap()));
+        assert!(trusted.contains("fd12::1".parse().unwrap()));
+        assert!(!trusted.contains("fe80::1".parse().unwrap()));
+
+        assert!("0.0.0.0/0".parse::<TrustedProxies>().unwrap().contains("203.0.113.7".parse().unwrap()));
+        assert!("".parse::<TrustedProxies>().unwrap().is_empty());
+        assert!("10.0.0.0/33".parse::<TrustedProxies>().is_err());
+        assert!("proxy.example.com".parse::<TrustedProxies>().is_err());
+    }
+
+    #[test]
+    fn test_parse_v1() {
+        let client: SocketAddr = "203.0.113.7:56324".parse().unwrap();
+        assert_eq!(parse_v1("PROXY TCP4 203.0.113.7 192.0.2.1 56324 443").unwrap(), Some(client));
+        assert_eq!(parse_v1("PROXY TCP6 2001:db8::1 2001:db8::2 56324 443").unwrap(), Some("[2001:db8::1]:56324".parse().unwrap()));
+        assert_eq!(parse_v1("PROXY UNKNOWN").unwrap(), None);
+
+        assert!(parse_v1("PROXY TCP4 2001:db8::1 192.0.2.1 56324 443").is_err());
+        assert!(parse_v1("PROXY TCP4 203.0.113.7 192.0.2.1 65536 443").is_err());
+        assert!(parse_v1("PROXY TCP4 203.0.113.7").is_err());
+    }
+
+    #[test]
+    fn test_read_header() {
+        task::block_on(async {
+            // Data after the header is left for TLS
+            let mut stream: &[u8] = b"PROXY TCP4 203.0.113.7 192.0.2.1 56324 443\r\n\x16\x03\x01";
+            assert_eq!(read_header(&mut stream).await.unwrap(), Some("203.0.113.7:56324".parse().unwrap()));
+            assert_eq!(stream, b"\x16\x03\x01");
+
+            let mut data = SIGNATURE.to_vec();
+            data.extend_from_slice(&[0x21, 0x11, 0, 12, 203, 0, 113, 7, 192, 0, 2, 1, 0xdc, 0x04, 0x01, 0xbb, 0x16]);
+            let mut stream = data.as_slice();
+            assert_eq!(read_header(&mut stream).await.unwrap(), Some("203.0.113.7:56324".parse().unwrap()));
+            assert_eq!(stream, b"\x16");
+
+            // UDP and unspecified protocols are not TCP connections
+            for protocol in [0x12, 0x10, 0x22] {
+                let mut data = SIGNATURE.to_vec();
+                data.extend_from_slice(&[0x21, protocol, 0, 12, 203, 0, 113, 7, 192, 0, 2, 1, 0xdc, 0x04, 0x01, 0xbb]);
+                assert!(read_header(&mut data.as_slice()).await.is_err());
+            }
+
+            // Health checks from the proxy itself
+            let mut data = SIGNATURE.to_vec();
+            data.extend_from_slice(&[0x20, 0x00, 0, 0]);
+            assert_eq!(read_header(&mut data.as_slice()).await.unwrap(), None);
+
+            let mut stream: &[u8] = b"\x16\x03\x01\x02\x00\x01\x00\x01\xfc\x03\x03\x00";
+            assert!(read_header(&mut stream).await.is_err());
+
+            let long = format!("PROXY TCP4 {}\r\n", "1".repeat(100));
+            assert!(read_header(&mut long.as_bytes()).await.is_err());
+        });
+    }
+}
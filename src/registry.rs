@@ -0,0 +1,342 @@
+use crate::api::event::Event;
+use crate::encoding::Format;
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use async_std::channel::{Sender, TrySendError};
+use log::warn;
+
+/// Conversations that a connection receives events for
+#[derive(Clone, Debug, PartialEq)]
+pub enum Subscriptions {
+    /// Every conversation the user participates in, including ones they join later, other than those listed
+    All {
+        except: HashSet<i32>,
+    },
+    /// Only the listed conversations
+    Only(HashSet<i32>),
+}
+
+impl Default for Subscriptions {
+    fn default() -> Self {
+        Subscriptions::All{except: HashSet::new()}
+    }
+}
+
+impl Subscriptions {
+    /// Check whether events for a conversation should be delivered
+    pub fn includes(&self, conversation: i32) -> bool {
+        match self {
+            Subscriptions::All{except} => !except.contains(&conversation),
+            Subscriptions::Only(conversations) => conversations.contains(&conversation),
+        }
+    }
+
+    /// Start receiving events for some conversations, or every conversation if none are listed
+    pub fn subscribe(&mut self, conversations: Option<&[i32]>) {
+        match (conversations, &mut *self) {
+            (None, _) => *self = Subscriptions::default(),
+            (Some(c), Subscriptions::All{..}) => *self = Subscriptions::Only(c.iter().copied().collect()),
+            (Some(c), Subscriptions::Only(existing)) => existing.extend(c),
+        }
+    }
+
+    /// Stop receiving events for some conversations, or every conversation if none are listed
+    ///
+    /// Unsubscribing from some conversations while subscribed to all of them leaves
+    /// the connection subscribed to every other conversation, including ones the user joins later.
+    pub fn unsubscribe(&mut self, conversations: Option<&[i32]>) {
+        match (conversations, &mut *self) {
+            (None, _) => *self = Subscriptions::Only(HashSet::new()),
+            (Some(c), Subscriptions::All{except}) => except.extend(c),
+            (Some(c), Subscriptions::Only(existing)) => {
+                for conversation in c {
+                    existing.remove(conversation);
+                }
+            },
+        }
+    }
+}
+
+/// An event about a conversation waiting to be published
+struct Pending {
+    conversation: i32,
+    participants: Vec<String>,
+    sender: Option<String>,
+    event: Event,
+}
+
+/// Events waiting to be published once the changes they describe have been committed
+#[derive(Default)]
+pub struct Outbox {
+    events: Vec<Pending>,
+}
+
+impl Outbox {
+    /// Queue an event about a conversation for its participants, other than the user who caused it
+    pub fn push(&mut self, conversation: i32, participants: Vec<String>, sender: Option<&str>, event: Event) {
+        self.events.push(Pending{
+            conversation,
+            participants,
+            sender: sender.map(String::from),
+            event,
+        });
+    }
+}
+
+/// An open connection that events can be pushed to
+struct Subscriber {
+    sender: Sender<Vec<u8>>,
+    /// Closed to disconnect the connection if it falls behind on events
+    disconnect: Sender<()>,
+    format: Format,
+    subscriptions: Subscriptions,
+}
+
+/// Connections that events can be sent to, keyed by connection
+type Connections = HashMap<usize, Subscriber>;
+
+/// Open connections that events can be pushed to, grouped by the user logged in on them
+#[derive(Clone, Default)]
+pub struct Registry {
+    users: Arc<Mutex<HashMap<String, Connections>>>,
+    next_id: Arc<AtomicUsize>,
+}
+
+impl Registry {
+    /// Reserve a unique identifier for a new connection
+    pub fn next_id(&self) -> usize {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Start pushing events for a user to a connection, written in the connection's format
+    pub fn register(&self, email: &str, connection: usize, sender: Sender<Vec<u8>>, disconnect: Sender<()>, format: Format) {
+        self.users.lock().unwrap()
+            .entry(email.to_owned())
+            .or_default()
+            .insert(connection, Subscriber{
+                sender,
+                disconnect,
+                format,
+                subscriptions: Subscriptions::default(),
+            });
+    }
+
+    /// Stop pushing events for a user to a connection
+    pub fn unregister(&self, email: &str, connection: usize) {
+        let mut users = self.users.lock().unwrap();
+
+        if let Some(connections) = users.get_mut(email) {
+            connections.remove(&connection);
+
+            if connections.is_empty() {
+                users.remove(email);
+            }
+        }
+    }
+
+    /// Change the conversations a connection receives events for, returning false if it is not registered
+    pub fn update_subscriptions<F>(&self, email: &str, connection: usize, update: F) -> bool
+    where
+        F: FnOnce(&mut Subscriptions),
+    {
+        let mut users = self.users.lock().unwrap();
+
+        match users.get_mut(email).and_then(|c| c.get_mut(&connection)) {
+            Some(subscriber) => {
+                update(&mut subscriber.subscriptions);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Push an event about a conversation to every subscribed connection of its participants
+    ///
+    /// Connections of users who are no longer participants stop being subscribed to the conversation,
+    /// and connections with too many frames waiting to be sent are disconnected, since they have missed events.
+    pub fn publish(&self, conversation: i32, participants: &[String], sender: Option<&str>, event: &Event) {
+        let mut users = self.users.lock().unwrap();
+        let mut encoded = HashMap::new();
+
+        for (email, connections) in users.iter_mut() {
+            let is_participant = participants.contains(email);
+
+            for (id, subscriber) in connections.iter_mut() {
+                if !is_participant {
+                    if let Subscriptions::Only(conversations) = &mut subscriber.subscriptions {
+                        conversations.remove(&conversation);
+                    }
+                } else if Some(email.as_str()) != sender && subscriber.subscriptions.includes(conversation) {
+                    // Each format is only written once, however many connections use it
+                    let data = encoded
+                        .entry(subscriber.format)
+                        .or_insert_with(|| subscriber.format.write(event));
+
+                    // Connections that are closing will be unregistered shortly
+                    if let Err(TrySendError::Full(_)) = subscriber.sender.try_send(data.clone()) {
+                        warn!("Disconnecting connection {} of {}: too far behind on events", id, email);
+                        subscriber.sender.close();
+                        subscriber.disconnect.close();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Push every event queued in an outbox
+    pub fn publish_all(&self, outbox: Outbox) {
+        for pending in outbox.events {
+            self.publish(pending.conversation, &pending.participants, pending.sender.as_deref(), &pending.event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::api::event::Event;
+    use crate::encoding::{Encoding, Format};
+    use crate::registry::{Outbox, Registry, Subscriptions};
+    use async_std::channel::{self, Sender};
+
+    fn event(name: &str) -> Event {
+        Event{
+            name: String::from(name),
+            users: None,
+            messages: None,
+            conversations: None,
+        }
+    }
+
+    fn json(name: &str) -> Vec<u8> {
+        Format::default().write(&event(name))
+    }
+
+    fn disconnect() -> Sender<()> {
+        channel::bounded(1).0
+    }
+
+    #[test]
+    fn test_publish() {
+        let registry = Registry::default();
+        let (phone, phone_events) = channel::unbounded();
+        let (laptop, laptop_events) = channel::unbounded();
+        let (other, other_events) = channel::unbounded();
+
+        let msgpack = Format{
+            encoding: Encoding::MessagePack,
+            compression: None,
+        };
+
+        let ids = [registry.next_id(), registry.next_id(), registry.next_id()];
+        assert_ne!(ids[0], ids[1]);
+
+        registry.register("1@example.com", ids[0], phone, disconnect(), Format::default());
+        registry.register("1@example.com", ids[1], laptop, disconnect(), msgpack);
+        registry.register("2@example.com", ids[2], other, disconnect(), Format::default());
+
+        let participants = vec![String::from("1@example.com"), String::from("2@example.com")];
+        registry.publish(4, &participants, Some("2@example.com"), &event("event"));
+        assert_eq!(phone_events.try_recv().unwrap(), json("event"));
+        assert_eq!(laptop_events.try_recv().unwrap(), msgpack.write(&event("event")));
+        assert!(other_events.try_recv().is_err());
+
+        registry.unregister("1@example.com", ids[0]);
+        registry.publish(4, &participants, None, &event("event"));
+        assert!(phone_events.try_recv().is_err());
+        assert!(laptop_events.try_recv().is_ok());
+        assert_eq!(other_events.try_recv().unwrap(), json("event"));
+    }
+
+    #[test]
+    fn test_publish_subscriptions() {
+        let registry = Registry::default();
+        let (sender, events) = channel::unbounded();
+        let id = registry.next_id();
+
+        assert!(!registry.update_subscriptions("1@example.com", id, |s| s.subscribe(Some(&[4]))));
+        registry.register("1@example.com", id, sender, disconnect(), Format::default());
+        assert!(registry.update_subscriptions("1@example.com", id, |s| s.subscribe(Some(&[4]))));
+
+        let participants = vec![String::from("1@example.com")];
+        registry.publish(5, &participants, None, &event("event"));
+        assert!(events.try_recv().is_err());
+        registry.publish(4, &participants, None, &event("event"));
+        assert_eq!(events.try_recv().unwrap(), json("event"));
+
+        // Removed participants lose their subscription
+        registry.publish(4, &[], None, &event("event"));
+        registry.publish(4, &participants, None, &event("event"));
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_publish_all() {
+        let registry = Registry::default();
+        let (sender, events) = channel::unbounded();
+        registry.register("1@example.com", registry.next_id(), sender, disconnect(), Format::default());
+
+        let mut outbox = Outbox::default();
+        outbox.push(4, vec![String::from("1@example.com")], None, event("first"));
+        outbox.push(4, vec![String::from("1@example.com")], Some("1@example.com"), event("own"));
+        outbox.push(5, vec![String::from("1@example.com")], None, event("second"));
+        assert!(events.try_recv().is_err());
+
+        registry.publish_all(outbox);
+        assert_eq!(events.try_recv().unwrap(), json("first"));
+        assert_eq!(events.try_recv().unwrap(), json("second"));
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_publish_slow_connection() {
+        let registry = Registry::default();
+        let (sender, events) = channel::bounded(2);
+        let (disconnect, disconnected) = channel::bounded(1);
+        registry.register("1@example.com", registry.next_id(), sender, disconnect, Format::default());
+
+        let participants = vec![String::from("1@example.com")];
+        registry.publish(4, &participants, None, &event("first"));
+        registry.publish(4, &participants, None, &event("second"));
+        assert!(!disconnected.is_closed());
+
+        // Connections that fall behind are disconnected once their queue is full
+        registry.publish(4, &participants, None, &event("third"));
+        assert!(disconnected.is_closed());
+        assert_eq!(events.try_recv().unwrap(), json("first"));
+        assert_eq!(events.try_recv().unwrap(), json("second"));
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_subscriptions() {
+        let mut subscriptions = Subscriptions::default();
+        assert!(subscriptions.includes(1));
+
+        subscriptions.subscribe(Some(&[1, 2]));
+        assert!(subscriptions.includes(1));
+        assert!(!subscriptions.includes(3));
+
+        subscriptions.subscribe(Some(&[3]));
+        subscriptions.unsubscribe(Some(&[1]));
+        assert!(!subscriptions.includes(1));
+        assert!(subscriptions.includes(2));
+        assert!(subscriptions.includes(3));
+
+        subscriptions.unsubscribe(None);
+        assert!(!subscriptions.includes(2));
+
+        subscriptions.subscribe(None);
+        assert_eq!(subscriptions, Subscriptions::default());
+
+        // Conversations joined after unsubscribing from others are still included
+        subscriptions.unsubscribe(Some(&[1]));
+        assert!(!subscriptions.includes(1));
+        assert!(subscriptions.includes(2));
+        assert!(subscriptions.includes(3));
+
+        subscriptions.unsubscribe(None);
+        assert!(!subscriptions.includes(3));
+    }
+}
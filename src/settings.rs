@@ -1,4 +1,5 @@
 use std::env;
+use std::str::FromStr;
 
 /// Check if a setting is on or off
 pub fn is_enabled(setting: &str) -> bool {
@@ -10,6 +11,14 @@ pub fn is_enabled(setting: &str) -> bool {
     false
 }
 
+/// Read a setting, falling back to a default if it is not set
+pub fn get_or<T: FromStr>(setting: &str, default: T) -> Result<T, T::Err> {
+    match env::var(setting) {
+        Ok(v) => v.parse(),
+        Err(_) => Ok(default),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::settings;
@@ -23,4 +32,14 @@ mod tests {
         assert_eq!(settings::is_enabled("ON"), true);
         assert_eq!(settings::is_enabled("OFF"), false);
     }
+
+    #[test]
+    fn test_get_or() {
+        env::set_var("NUMBER", "42");
+        env::set_var("NOT_A_NUMBER", "abc");
+
+        assert_eq!(settings::get_or("NUMBER", 0u32), Ok(42));
+        assert_eq!(settings::get_or("UNSET_NUMBER", 7u32), Ok(7));
+        assert!(settings::get_or("NOT_A_NUMBER", 0u32).is_err());
+    }
 }
\ No newline at end of file
@@ -0,0 +1,130 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use async_std::channel::{self, Receiver, Sender};
+use async_std::future;
+use async_std::task;
+
+/// How often to check whether tracked work has finished
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A count of work in progress that can be waited on
+#[derive(Clone, Default)]
+pub struct Tracker {
+    active: Arc<AtomicUsize>,
+}
+
+/// Work counted by a tracker until it is dropped
+pub struct Active {
+    active: Arc<AtomicUsize>,
+}
+
+impl Tracker {
+    /// Count work as in progress until the returned guard is dropped
+    pub fn track(&self) -> Active {
+        self.active.fetch_add(1, Ordering::SeqCst);
+
+        Active{
+            active: self.active.clone(),
+        }
+    }
+
+    /// Check whether any work is in progress
+    pub fn is_idle(&self) -> bool {
+        self.active.load(Ordering::SeqCst) == 0
+    }
+
+    /// Wait until no work is in progress
+    pub async fn wait_idle(&self) {
+        while !self.is_idle() {
+            task::sleep(POLL_INTERVAL).await;
+        }
+    }
+}
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A signal telling connections to finish up because the server is stopping
+#[derive(Clone)]
+pub struct Shutdown {
+    sender: Sender<()>,
+    receiver: Receiver<()>,
+    connections: Tracker,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        let (sender, receiver) = channel::bounded(1);
+
+        Shutdown{
+            sender,
+            receiver,
+            connections: Tracker::default(),
+        }
+    }
+}
+
+impl Shutdown {
+    /// Tell every connection that the server is stopping, returning false if it already was
+    pub fn start(&self) -> bool {
+        // Nothing is ever sent, so closing the channel wakes every waiting receiver
+        self.sender.close()
+    }
+
+    /// Wait until the server starts stopping
+    pub async fn wait(&self) {
+        self.receiver.recv().await.ok();
+    }
+
+    /// Count a connection as open until the returned guard is dropped
+    pub fn track_connection(&self) -> Active {
+        self.connections.track()
+    }
+
+    /// Wait for open connections to close, returning false if the deadline passes first
+    pub async fn drain(&self, deadline: Duration) -> bool {
+        future::timeout(deadline, self.connections.wait_idle()).await.is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::shutdown::{Shutdown, Tracker};
+    use std::time::Duration;
+    use async_std::task;
+
+    #[test]
+    fn test_tracker() {
+        let tracker = Tracker::default();
+        assert!(tracker.is_idle());
+
+        let first = tracker.track();
+        let second = tracker.track();
+        drop(first);
+        assert!(!tracker.is_idle());
+
+        drop(second);
+        assert!(tracker.is_idle());
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let shutdown = Shutdown::default();
+
+        let connection = shutdown.track_connection();
+        assert!(shutdown.start());
+        assert!(!shutdown.start());
+
+        task::block_on(async {
+            shutdown.wait().await;
+            assert!(!shutdown.drain(Duration::from_millis(10)).await);
+
+            drop(connection);
+            assert!(shutdown.drain(Duration::from_millis(500)).await);
+        });
+    }
+}
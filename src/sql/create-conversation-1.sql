@@ -1,2 +1,3 @@
 INSERT INTO conversations (name)
-VALUES ($1)
\ No newline at end of file
+VALUES ($1)
+RETURNING id
\ No newline at end of file
@@ -7,4 +7,5 @@ VALUES (
     WHERE users.email = $1
     AND conversations.id = $2),
     $3, $4, $5, $6
-)
\ No newline at end of file
+)
+RETURNING id
\ No newline at end of file
@@ -0,0 +1,5 @@
+INSERT INTO sessions (token_hash, identity, client, address, created, last_seen, expires)
+SELECT $2, users.id, $3, $4, $5, $5, $6
+FROM users
+WHERE users.email = $1
+RETURNING id
\ No newline at end of file
@@ -1,2 +1,3 @@
 INSERT INTO users (email, public_key, pass, salt)
-VALUES ($1, $2, $3, $4)
\ No newline at end of file
+VALUES ($1, $2, $3, $4)
+RETURNING id
\ No newline at end of file
@@ -0,0 +1,6 @@
+DELETE FROM sessions
+USING users
+WHERE (users.id = sessions.identity)
+AND (users.email = $1)
+AND (sessions.id IS DISTINCT FROM $2)
+RETURNING sessions.id
\ No newline at end of file
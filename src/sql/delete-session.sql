@@ -0,0 +1,6 @@
+DELETE FROM sessions
+USING users
+WHERE (sessions.id = $2)
+AND (users.id = sessions.identity)
+AND (users.email = $1)
+RETURNING sessions.id
\ No newline at end of file
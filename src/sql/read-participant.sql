@@ -0,0 +1,9 @@
+SELECT conversations.id
+FROM conversations
+WHERE (conversations.id = $2)
+AND ($2 IN (
+    SELECT conversation
+    FROM participants
+    JOIN users ON users.id = participants.identity
+    WHERE users.email = $1
+))
\ No newline at end of file
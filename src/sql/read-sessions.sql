@@ -0,0 +1,6 @@
+SELECT sessions.id, sessions.client, sessions.address, sessions.created, sessions.last_seen, sessions.expires
+FROM sessions
+JOIN users ON users.id = sessions.identity
+WHERE (users.email = $1)
+AND (sessions.expires > $2)
+ORDER BY sessions.last_seen DESC
\ No newline at end of file
@@ -0,0 +1,7 @@
+UPDATE sessions
+SET last_seen = $2
+FROM users
+WHERE (sessions.token_hash = $1)
+AND (sessions.expires > $2)
+AND (users.id = sessions.identity)
+RETURNING sessions.id, users.email, sessions.expires
\ No newline at end of file
@@ -1 +1 @@
-DROP TABLE IF EXISTS messages, participants, conversations, users CASCADE
\ No newline at end of file
+DROP TABLE IF EXISTS sessions, messages, participants, conversations, users CASCADE
\ No newline at end of file
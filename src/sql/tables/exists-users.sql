@@ -0,0 +1 @@
+SELECT to_regclass('users') IS NOT NULL AS "exists!"
\ No newline at end of file
@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS sessions (
+    id SERIAL PRIMARY KEY,
+    token_hash BYTEA UNIQUE NOT NULL,
+    identity INT references users(id) NOT NULL,
+    client VARCHAR(64),
+    address VARCHAR(45),
+    created BIGINT NOT NULL,
+    last_seen BIGINT NOT NULL,
+    expires BIGINT NOT NULL
+)
\ No newline at end of file
@@ -0,0 +1,79 @@
+use crate::{Connection, Server, Transport};
+use crate::api::error::ApiError;
+use crate::encoding::Format;
+use crate::frame::ReadError;
+use crate::listener::Peer;
+use crate::settings;
+
+use std::error::Error;
+use std::io::Error as ioErr;
+use std::io::ErrorKind as ioErrKind;
+use async_std::channel;
+use async_std::io::{Read, Write};
+use async_std::task;
+use async_tungstenite::tungstenite::{Error as WsError, Message};
+use async_tungstenite::tungstenite::protocol::WebSocketConfig;
+use futures::{future, SinkExt, StreamExt};
+
+/// Upgrade a connection to a WebSocket only to send a client an error, then close it
+pub async fn refuse_stream<S>(stream: S, error: ApiError) -> Result<(), Box<dyn Error>>
+where
+    S: Read + Write + Send + Unpin + 'static,
+{
+    let mut socket = async_tungstenite::accept_async(stream).await?;
+    let response = String::from_utf8(crate::format_error(error, Format::default()))?;
+
+    socket.send(Message::Text(response)).await?;
+    socket.close(None).await?;
+    Ok(())
+}
+
+/// Exchange requests and responses with a client over a WebSocket
+pub async fn handle_stream<S>(stream: S, peer: Peer, server: &Server) -> Result<(), Box<dyn Error>>
+where
+    S: Read + Write + Send + Unpin + 'static,
+{
+    let max_size = settings::get_or("MAX_FRAME_SIZE", 1048576)?;
+    let config = WebSocketConfig{
+        max_message_size: Some(max_size),
+        max_frame_size: Some(max_size),
+        ..WebSocketConfig::default()
+    };
+
+    // Upgrade connection to a WebSocket
+    let socket = async_tungstenite::accept_async_with_config(stream, Some(config)).await?;
+    let (mut sink, stream) = socket.split();
+    let (sender, receiver) = channel::bounded::<Vec<u8>>(crate::queue_size()?);
+
+    // Each text or binary message holds a single request
+    let frames = stream
+        .take_while(|message| future::ready(!matches!(message, Ok(m) if m.is_close())))
+        .filter_map(|message| future::ready(match message {
+            Ok(Message::Text(text)) => Some(Ok(text.into_bytes())),
+            Ok(Message::Binary(data)) => Some(Ok(data)),
+            Ok(_) => None,
+            Err(WsError::Io(e)) => Some(Err(ReadError::Closed(e))),
+            Err(e) => Some(Err(ReadError::Closed(ioErr::new(ioErrKind::InvalidData, e)))),
+        }));
+
+    // Send responses in the order they are completed
+    let writing = task::spawn(async move {
+        while let Ok(response) = receiver.recv().await {
+            // JSON is always valid text, while MessagePack never is, since it starts with a map or array
+            let message = match String::from_utf8(response) {
+                Ok(text) => Message::Text(text),
+                Err(e) => Message::Binary(e.into_bytes()),
+            };
+            sink.send(message).await?;
+        }
+        sink.close().await
+    });
+
+    // Stop sending once every request has been answered
+    let connection = Connection::new(server, sender, Transport::WebSocket, peer);
+    let result = crate::handle_requests(frames, &connection).await.map_err(|e| e.to_string());
+    drop(connection);
+
+    writing.await?;
+    Ok(result?)
+}
\ No newline at end of file